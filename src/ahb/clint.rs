@@ -1,19 +1,26 @@
-use volatile_register::{RW};
-
-// CLINT Construction Check
-pub static mut CLINT_CONSTRUCTED: bool = false;
+use crate::peripherals::{self, AlreadyTaken};
+use crate::register::{self, Block, RW};
 
 // CLINT Constants
 pub const CLINT: u32 =                  0xE0000000;
+#[allow(clippy::identity_op)]
 pub const CLINT_MSIP: u32 =             CLINT + 0x00;
 pub const CLINT_MTIME: u32 =            CLINT + 0x04;
 pub const CLINT_MTIMECMP: u32 =         CLINT + 0x0C;
 pub const CLINT_MSIP_DISABLE: u32 =     !(1 << 0);
 pub const CLINT_MSIP_ENABLE: u32 =      1 << 0;
 pub const CLINT_MSIP_MASK: u32 =        1 << 0;
+pub const CLINT_MTIMECMP_DISARMED: u64 = u64::MAX;
 
 pub struct CLINT {
-    p: &'static mut CLINTRegisterBlock
+    p: Block<CLINTRegisterBlock>
+}
+
+// Read-only view of mtime. It never writes a register, so any number of
+// drivers can hold a copy next to the CLINT owner.
+#[derive(Clone, Copy)]
+pub struct MTime {
+    p: Block<CLINTRegisterBlock>
 }
 
 #[repr(C)]
@@ -27,16 +34,36 @@ struct CLINTRegisterBlock {
 
 impl CLINT {
     pub fn new() -> CLINT {
+        match CLINT::try_new() {
+            Ok(clint) => clint,
+            Err(_) =>   panic!("You may construct only one instance of CLINT."),
+        }
+    }
+
+    pub fn try_new() -> Result<CLINT, AlreadyTaken> {
+        if peripherals::claim(peripherals::CLINT_TAKEN) {
+            Ok(unsafe { CLINT::steal() })
+        }
+        else {
+            Err(AlreadyTaken)
+        }
+    }
+
+    // Returns the peripheral to its reset state so it can be constructed again
+    pub fn free(mut self) {
         unsafe {
-            if CLINT_CONSTRUCTED == false {
-                CLINT_CONSTRUCTED = true;
-                CLINT {
-                    p: &mut *(0xE000_0000 as *mut CLINTRegisterBlock) 
-                }
-            }
-            else { 
-                panic!("You may construct only one instance of CLINT.")
-            }
+            self.p.msip.write(0);
+        }
+        self.disarm_timer();
+        peripherals::release(peripherals::CLINT_TAKEN);
+    }
+
+    /// # Safety
+    /// Skips the ownership check. The caller must not race the owning instance,
+    /// e.g. by only using the stolen copy inside an interrupt handler.
+    pub unsafe fn steal() -> CLINT {
+        CLINT {
+            p: register::block(0xE000_0000)
         }
     }
 
@@ -59,4 +86,139 @@ impl CLINT {
             self.p.msip.write(curr);
         }
     }
-}
\ No newline at end of file
+
+    pub fn mtime(&self) -> MTime {
+        MTime { p: self.p }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.mtime().now()
+    }
+
+    pub fn get_compare(&self) -> u64 {
+        ((self.p.mtimecmph.read() as u64) << 32) | (self.p.mtimecmpl.read() as u64)
+    }
+
+    // Parks mtimecmpl at its maximum first so no intermediate value can fire early
+    pub fn set_compare(&mut self, value: u64) {
+        unsafe {
+            self.p.mtimecmpl.write(u32::MAX);
+            self.p.mtimecmph.write((value >> 32) as u32);
+            self.p.mtimecmpl.write(value as u32);
+        }
+    }
+
+    // The arm helpers only program mtimecmp. Taking the interrupt also needs
+    // MTIE in the mie CSR and MIE in mstatus, which belong to the hart rather
+    // than the CLINT and are left to the application or its runtime crate.
+    pub fn arm_timer(&mut self, ticks: u64) {
+        let deadline: u64 = self.now().saturating_add(ticks);
+        self.set_compare(deadline);
+    }
+
+    pub fn arm_timer_at(&mut self, deadline: u64) {
+        self.set_compare(deadline);
+    }
+
+    pub fn disarm_timer(&mut self) {
+        self.set_compare(CLINT_MTIMECMP_DISARMED);
+    }
+
+    pub fn timer_armed(&self) -> bool {
+        self.get_compare() != CLINT_MTIMECMP_DISARMED
+    }
+
+    pub fn timer_expired(&self) -> bool {
+        self.now() >= self.get_compare()
+    }
+}
+
+impl MTime {
+    // Reads mtime, retrying if mtimel rolled over between the two halves
+    pub fn now(&self) -> u64 {
+        loop {
+            let hi: u32 = self.p.mtimeh.read();
+            let lo: u32 = self.p.mtimel.read();
+            if hi == self.p.mtimeh.read() {
+                return ((hi as u64) << 32) | (lo as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Backend};
+
+    // mtime that advances by one tick on every register read, so it moves
+    // between the reads of the two halves
+    struct Ticking {
+        mtime: u64
+    }
+
+    impl Backend for Ticking {
+        fn read(&mut self, address: u32) -> u32 {
+            let value: u32 = match address {
+                CLINT_MTIME =>                  (self.mtime >> 32) as u32,
+                a if a == CLINT_MTIME + 0x04 => self.mtime as u32,
+                _ =>                            0,
+            };
+            self.mtime += 1;
+            value
+        }
+
+        fn write(&mut self, _address: u32, _value: u32) {}
+    }
+
+    #[test]
+    fn now_combines_halves() {
+        let session = mock::session();
+        let clint = CLINT::new();
+        session.write(CLINT_MTIME, 0x1);
+        session.write(CLINT_MTIME + 0x04, 0x2345_6789);
+        assert_eq!(clint.now(), 0x1_2345_6789);
+    }
+
+    #[test]
+    fn now_survives_low_word_rollover() {
+        let session = mock::session();
+        let clint = CLINT::new();
+        // Every start offset, so one of the calls reads mtimeh just before the wrap
+        for start in 0xFFFF_FFF0..0xFFFF_FFF8 {
+            session.install(Ticking { mtime: start });
+            let mut last: u64 = start;
+            while last < 0x1_0000_0010 {
+                let now: u64 = clint.now();
+                assert!(now > last, "mtime went from {:#x} back to {:#x}", last, now);
+                last = now;
+            }
+        }
+    }
+
+    #[test]
+    fn compare_and_arming() {
+        let session = mock::session();
+        let mut clint = CLINT::new();
+        clint.set_compare(0xAB_0000_0010);
+        assert_eq!(session.read(CLINT_MTIMECMP), 0xAB);
+        assert_eq!(session.read(CLINT_MTIMECMP + 0x04), 0x10);
+        assert!(clint.timer_armed());
+        clint.disarm_timer();
+        assert!(!clint.timer_armed());
+        session.write(CLINT_MTIME + 0x04, 100);
+        clint.arm_timer(50);
+        assert_eq!(clint.get_compare(), 150);
+        assert!(!clint.timer_expired());
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let session = mock::session();
+        let mut clint = CLINT::new();
+        clint.set_interrupt();
+        assert_eq!(session.read(CLINT_MSIP), CLINT_MSIP_ENABLE);
+        clint.clear_interrupt();
+        assert_eq!(clint.interrupt_status(), 0);
+    }
+}
@@ -1,10 +1,10 @@
-use volatile_register::{RW};
-
-// PLIC Construction Check
-pub static mut PLIC_CONSTRUCTED: bool =  false;
+use crate::common::{self, Error};
+use crate::peripherals::{self, AlreadyTaken};
+use crate::register::{self, Block, RW};
 
 // PLIC Constants
-pub const PLIC: u32 =        0xE0000000;
+pub const PLIC: u32 =        0xE0010000;
+#[allow(clippy::identity_op)]
 pub const PLIC_RES1: u32 =   PLIC + 0x00;
 pub const PLIC_IPR1: u32 =   PLIC + 0x04;
 pub const PLIC_IPR2: u32 =   PLIC + 0x08;
@@ -43,46 +43,53 @@ pub const PLIC_IER: u32 =    PLIC + 0x88;
 pub const PLIC_RES2: u32 =   PLIC + 0x8C;
 pub const PLIC_PTR: u32 =    PLIC + 0x90;
 pub const PLIC_CCRL: u32 =   PLIC + 0x94;
+pub const PLIC_SOURCES: u32 = 32;
+pub const PLIC_NO_INTERRUPT: u32 = 0;
+
+// PLIC Interrupt Sources
+// A source ID from 1 to PLIC_SOURCES. Which peripheral drives which ID is fixed
+// by the SoC wiring, which this crate does not document, so sources are only
+// numbered. Look the ID up in the AFTx06 RTL or memory map.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptSource {
+    Raw(u32),
+}
+
+impl InterruptSource {
+    // None unless id is one of the PLIC_SOURCES source IDs
+    pub fn from_id(id: u32) -> Option<InterruptSource> {
+        if (1..=PLIC_SOURCES).contains(&id) {
+            Some(InterruptSource::Raw(id))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn id(self) -> u32 {
+        match self {
+            InterruptSource::Raw(id) => id,
+        }
+    }
+
+    pub fn is_valid(self) -> bool {
+        InterruptSource::from_id(self.id()).is_some()
+    }
+
+    // Bit of the source in IER and IPNDGR; zero for an invalid ID
+    pub fn mask(self) -> u32 {
+        if self.is_valid() { common::plicn(self.id()) } else { 0 }
+    }
+}
 
 pub struct PLIC {
-    p: &'static mut PLICRegisterBlock
+    p: Block<PLICRegisterBlock>
 }
 
 #[repr(C)]
 struct PLICRegisterBlock {
     pub res1:   u32,
-    pub ipr1:   RW<u32>,
-    pub ipr2:   RW<u32>,
-    pub ipr3:   RW<u32>,
-    pub ipr4:   RW<u32>,
-    pub ipr5:   RW<u32>,
-    pub ipr6:   RW<u32>,
-    pub ipr7:   RW<u32>,
-    pub ipr8:   RW<u32>,
-    pub ipr9:   RW<u32>,
-    pub ipr10:  RW<u32>,
-    pub ipr11:  RW<u32>,
-    pub ipr12:  RW<u32>,
-    pub ipr13:  RW<u32>,
-    pub ipr14:  RW<u32>,
-    pub ipr15:  RW<u32>,
-    pub ipr16:  RW<u32>,
-    pub ipr17:  RW<u32>,
-    pub ipr18:  RW<u32>,
-    pub ipr19:  RW<u32>,
-    pub ipr20:  RW<u32>,
-    pub ipr21:  RW<u32>,
-    pub ipr22:  RW<u32>,
-    pub ipr23:  RW<u32>,
-    pub ipr24:  RW<u32>,
-    pub ipr25:  RW<u32>,
-    pub ipr26:  RW<u32>,
-    pub ipr27:  RW<u32>,
-    pub ipr28:  RW<u32>,
-    pub ipr29:  RW<u32>,
-    pub ipr30:  RW<u32>,
-    pub ipr31:  RW<u32>,
-    pub ipr32:  RW<u32>,
+    pub ipr:    [RW<u32>; PLIC_SOURCES as usize],
     pub ipndgr: RW<u32>,
     pub ier:    RW<u32>,
     pub res2:   u32,
@@ -92,16 +99,192 @@ struct PLICRegisterBlock {
 
 impl PLIC {
     pub fn new() -> PLIC {
+        match PLIC::try_new() {
+            Ok(plic) => plic,
+            Err(_) =>  panic!("You may construct only one instance of PLIC."),
+        }
+    }
+
+    pub fn try_new() -> Result<PLIC, AlreadyTaken> {
+        if peripherals::claim(peripherals::PLIC_TAKEN) {
+            Ok(unsafe { PLIC::steal() })
+        }
+        else {
+            Err(AlreadyTaken)
+        }
+    }
+
+    // Returns the peripheral to its reset state so it can be constructed again
+    pub fn free(self) {
         unsafe {
-            if PLIC_CONSTRUCTED == false {
-                PLIC_CONSTRUCTED = true;
-                PLIC {
-                    p: &mut *(0xE001_0000 as *mut PLICRegisterBlock)
-                }
-            }
-            else {
-                panic!("You may construct only one instance of PLIC.")
+            self.p.ier.write(0);
+            self.p.ptr.write(0);
+        }
+        for ipr in self.p.ipr.iter() {
+            unsafe {
+                ipr.write(0);
             }
         }
+        peripherals::release(peripherals::PLIC_TAKEN);
+    }
+
+    /// # Safety
+    /// Skips the ownership check. The caller must not race the owning instance,
+    /// e.g. by only using the stolen copy inside an interrupt handler.
+    pub unsafe fn steal() -> PLIC {
+        PLIC {
+            p: register::block(0xE001_0000)
+        }
+    }
+
+    pub fn set_priority(&mut self, source: InterruptSource, priority: u32) -> Result<(), Error> {
+        unsafe {
+            self.ipr(source)?.write(priority);
+        }
+        Ok(())
+    }
+
+    pub fn get_priority(&self, source: InterruptSource) -> Result<u32, Error> {
+        Ok(self.ipr(source)?.read())
+    }
+
+    pub fn enable_interrupt(&mut self, source: InterruptSource) -> Result<(), Error> {
+        let mask: u32 = PLIC::checked(source)?.mask();
+        unsafe {
+            let mut curr: u32 = self.p.ier.read();
+            curr |= mask;
+            self.p.ier.write(curr);
+        }
+        Ok(())
+    }
+
+    pub fn enable_interrupts(&mut self, sources: u32) {
+        unsafe {
+            let mut curr: u32 = self.p.ier.read();
+            curr |= sources;
+            self.p.ier.write(curr);
+        }
+    }
+
+    pub fn disable_interrupt(&mut self, source: InterruptSource) -> Result<(), Error> {
+        let mask: u32 = PLIC::checked(source)?.mask();
+        unsafe {
+            let mut curr: u32 = self.p.ier.read();
+            curr &= !mask;
+            self.p.ier.write(curr);
+        }
+        Ok(())
+    }
+
+    pub fn disable_interrupts(&mut self, sources: u32) {
+        unsafe {
+            let mut curr: u32 = self.p.ier.read();
+            curr &= !sources;
+            self.p.ier.write(curr);
+        }
+    }
+
+    pub fn interrupt_enabled(&self, source: InterruptSource) -> u32 {
+        self.p.ier.read() & source.mask()
+    }
+
+    pub fn interrupts_enabled(&self) -> u32 {
+        self.p.ier.read()
+    }
+
+    pub fn set_threshold(&mut self, threshold: u32) {
+        unsafe {
+            self.p.ptr.write(threshold);
+        }
+    }
+
+    pub fn get_threshold(&self) -> u32 {
+        self.p.ptr.read()
+    }
+
+    pub fn interrupt_pending(&self, source: InterruptSource) -> u32 {
+        self.p.ipndgr.read() & source.mask()
+    }
+
+    pub fn interrupts_pending(&self) -> u32 {
+        self.p.ipndgr.read()
+    }
+
+    // Returns the highest priority pending source, if any
+    pub fn claim(&mut self) -> Option<InterruptSource> {
+        InterruptSource::from_id(self.p.ccr.read())
+    }
+
+    pub fn complete(&mut self, source: InterruptSource) -> Result<(), Error> {
+        let id: u32 = PLIC::checked(source)?.id();
+        unsafe {
+            self.p.ccr.write(id);
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn checked(source: InterruptSource) -> Result<InterruptSource, Error> {
+        if source.is_valid() { Ok(source) } else { Err(Error::InvalidSource) }
+    }
+
+    fn ipr(&self, source: InterruptSource) -> Result<&RW<u32>, Error> {
+        let id: u32 = PLIC::checked(source)?.id();
+        Ok(&self.p.ipr[(id - 1) as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn priorities() {
+        let session = mock::session();
+        let mut plic = PLIC::new();
+        plic.set_priority(InterruptSource::Raw(1), 3).unwrap();
+        plic.set_priority(InterruptSource::Raw(32), 7).unwrap();
+        assert_eq!(session.read(PLIC_IPR1), 3);
+        assert_eq!(session.read(PLIC_IPR32), 7);
+        assert_eq!(plic.get_priority(InterruptSource::Raw(32)), Ok(7));
+    }
+
+    #[test]
+    fn enables_and_threshold() {
+        let session = mock::session();
+        let mut plic = PLIC::new();
+        plic.enable_interrupt(InterruptSource::Raw(2)).unwrap();
+        plic.enable_interrupt(InterruptSource::Raw(32)).unwrap();
+        assert_eq!(session.read(PLIC_IER), (1 << 1) | (1 << 31));
+        plic.disable_interrupt(InterruptSource::Raw(2)).unwrap();
+        assert_eq!(plic.interrupts_enabled(), 1 << 31);
+        plic.set_threshold(2);
+        assert_eq!(session.read(PLIC_PTR), 2);
+    }
+
+    #[test]
+    fn claim_and_complete() {
+        let session = mock::session();
+        let mut plic = PLIC::new();
+        assert_eq!(plic.claim(), None);
+        session.write(PLIC_CCRL, 4);
+        assert_eq!(plic.claim(), Some(InterruptSource::Raw(4)));
+        plic.complete(InterruptSource::Raw(1)).unwrap();
+        assert_eq!(session.read(PLIC_CCRL), 1);
+    }
+
+    #[test]
+    fn invalid_sources_are_rejected() {
+        let session = mock::session();
+        let mut plic = PLIC::new();
+        assert_eq!(InterruptSource::from_id(0), None);
+        assert_eq!(InterruptSource::from_id(PLIC_SOURCES + 1), None);
+        assert_eq!(InterruptSource::Raw(0).mask(), 0);
+        assert_eq!(plic.set_priority(InterruptSource::Raw(33), 1), Err(Error::InvalidSource));
+        assert_eq!(plic.get_priority(InterruptSource::Raw(0)), Err(Error::InvalidSource));
+        assert_eq!(plic.enable_interrupt(InterruptSource::Raw(0)), Err(Error::InvalidSource));
+        assert_eq!(plic.complete(InterruptSource::Raw(0)), Err(Error::InvalidSource));
+        assert_eq!(session.read(PLIC_IER), 0);
+        assert_eq!(session.read(PLIC_CCRL), 0);
+    }
+}
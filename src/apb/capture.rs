@@ -0,0 +1,321 @@
+use crate::apb::timer::{CaptureEdge, InterruptMode, Pre, TIMChannel, Timeline};
+use crate::common::{self};
+use crate::time::{Duration};
+
+// One full cycle of a captured signal, in prescaled counter ticks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PulseMeasurement {
+    pub period_ticks: u64,
+    pub high_ticks: u64,
+    pub low_ticks: u64,
+    pub prescaler: Pre,
+}
+
+impl PulseMeasurement {
+    pub fn period(&self) -> Duration {
+        self.duration(self.period_ticks)
+    }
+
+    pub fn high_time(&self) -> Duration {
+        self.duration(self.high_ticks)
+    }
+
+    pub fn low_time(&self) -> Duration {
+        self.duration(self.low_ticks)
+    }
+
+    // High time as parts per million of the period
+    pub fn duty_ppm(&self) -> u32 {
+        if self.period_ticks == 0 { return 0; }
+        ((self.high_ticks as u128 * 1_000_000) / self.period_ticks as u128) as u32
+    }
+
+    pub fn duty_percent(&self) -> u8 {
+        common::rounding_division(self.duty_ppm(), 10_000) as u8
+    }
+
+    fn duration(&self, ticks: u64) -> Duration {
+        Duration::from_ticks(ticks.saturating_mul(self.prescaler.divider() as u64))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CaptureState {
+    active: bool,
+    edge: CaptureEdge,
+    rise: Option<u64>,
+    fall: Option<u64>,
+    latest: Option<PulseMeasurement>,
+}
+
+impl CaptureState {
+    const IDLE: CaptureState = CaptureState {
+        active: false,
+        edge: CaptureEdge::Disabled,
+        rise: None,
+        fall: None,
+        latest: None,
+    };
+}
+
+// Measures period and high/low time on one TIM input capture channel.
+// The counter must be free running (see TIMCounter::start_free_running) and
+// every capture is extended to 64 bits from the counter reads of this driver.
+// The channel alternates between rising and falling edge capture, so poll()
+// must run at least once per edge and at least once per counter wrap.
+pub struct Capture {
+    ch: TIMChannel,
+    timeline: Timeline,
+    state: CaptureState,
+}
+
+impl Capture {
+    pub fn new(ch: TIMChannel) -> Capture {
+        let timeline: Timeline = Timeline::new(ch.read_count());
+        Capture { ch, timeline, state: CaptureState::IDLE }
+    }
+
+    pub fn start(&mut self) {
+        self.ch.set_input_capture(CaptureEdge::Rising, InterruptMode::Disabled);
+        self.ch.clear_interrupt();
+        self.timeline = Timeline::new(self.ch.read_count());
+        self.state = CaptureState {
+            active: true,
+            edge: CaptureEdge::Rising,
+            ..CaptureState::IDLE
+        };
+    }
+
+    pub fn stop(&mut self) {
+        self.ch.set_input_capture_edge(CaptureEdge::Disabled);
+        self.ch.clear_interrupt();
+        self.state.active = false;
+    }
+
+    // Collects a pending capture and returns true when it completes a measurement.
+    // The capture is read before the counter, so it is never newer than the count
+    // it is placed against.
+    pub fn poll(&mut self) -> bool {
+        let value: Option<u32> = if self.state.active && self.ch.interrupt_flag() {
+            let value: u32 = self.ch.read_input_capture();
+            self.ch.clear_interrupt();
+            Some(value)
+        } else {
+            None
+        };
+        self.timeline.update(self.ch.read_count());
+        let value: u32 = match value {
+            Some(value) => value,
+            None => return false,
+        };
+        let prescaler: Pre = self.ch.get_prescaler();
+        let complete: bool = self.record(self.timeline.extend(value), prescaler);
+        self.ch.set_input_capture_edge(self.state.edge);
+        complete
+    }
+
+    pub fn measurement(&self) -> Option<PulseMeasurement> {
+        self.state.latest
+    }
+
+    pub fn channel(&self) -> &TIMChannel {
+        &self.ch
+    }
+
+    pub fn free(mut self) -> TIMChannel {
+        self.stop();
+        self.ch
+    }
+
+    // Stores a capture and flips the edge; returns true once a full cycle is known
+    fn record(&mut self, timestamp: u64, prescaler: Pre) -> bool {
+        let state: &mut CaptureState = &mut self.state;
+        match state.edge {
+            CaptureEdge::Rising => {
+                let mut complete: bool = false;
+                if let (Some(rise), Some(fall)) = (state.rise, state.fall) {
+                    state.latest = Some(PulseMeasurement {
+                        period_ticks: timestamp - rise,
+                        high_ticks: fall - rise,
+                        low_ticks: timestamp - fall,
+                        prescaler,
+                    });
+                    complete = true;
+                }
+                state.rise = Some(timestamp);
+                state.fall = None;
+                state.edge = CaptureEdge::Falling;
+                complete
+            }
+            _ => {
+                if state.rise.is_some() {
+                    state.fall = Some(timestamp);
+                }
+                state.edge = CaptureEdge::Rising;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apb::timer::{Parts, TIM, TIM_TCNT};
+    use crate::mock::{self, sim::Simulator};
+
+    // Drives one full cycle on a channel, polling after every edge
+    fn cycle(session: &mock::Session, capture: &mut Capture, high: u64, low: u64) -> bool {
+        let n: u32 = capture.channel().channel() as u32;
+        session.with(|sim: &mut Simulator| sim.set_timer_input(n, true));
+        let mut complete: bool = capture.poll();
+        session.with(|sim: &mut Simulator| sim.step(high));
+        complete |= capture.poll();
+        session.with(|sim: &mut Simulator| sim.set_timer_input(n, false));
+        complete |= capture.poll();
+        session.with(|sim: &mut Simulator| sim.step(low));
+        complete |= capture.poll();
+        complete
+    }
+
+    fn free_running(pre_div: Pre) -> Parts {
+        let mut parts = TIM::new().split();
+        parts.counter.set_prescaler(pre_div);
+        parts.counter.start_free_running();
+        parts
+    }
+
+    #[test]
+    fn measures_period_and_duty() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        let mut capture = Capture::new(parts.ch2);
+        capture.start();
+        session.with(|sim: &mut Simulator| sim.step(10));
+        assert!(!cycle(&session, &mut capture, 30, 70));
+        assert_eq!(capture.measurement(), None);
+        assert!(cycle(&session, &mut capture, 25, 75));
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 100);
+        assert_eq!(measurement.high_ticks, 30);
+        assert_eq!(measurement.low_ticks, 70);
+        assert_eq!(measurement.duty_percent(), 30);
+        assert_eq!(measurement.duty_ppm(), 300_000);
+        assert_eq!(measurement.period(), Duration::from_ticks(100));
+    }
+
+    #[test]
+    fn durations_scale_with_prescaler() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV4);
+        let mut capture = Capture::new(parts.ch0);
+        capture.start();
+        cycle(&session, &mut capture, 400, 400);
+        cycle(&session, &mut capture, 400, 400);
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 200);
+        assert_eq!(measurement.high_time(), Duration::from_ticks(400));
+        assert_eq!(measurement.low_time(), Duration::from_ticks(400));
+        assert_eq!(measurement.period(), Duration::from_micros(8));
+    }
+
+    #[test]
+    fn period_spans_counter_overflow() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        session.write(TIM_TCNT, u32::MAX - 120);
+        let mut capture = Capture::new(parts.ch5);
+        capture.start();
+        cycle(&session, &mut capture, 40, 60);
+        cycle(&session, &mut capture, 40, 60);
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 100);
+        assert_eq!(measurement.high_ticks, 40);
+        assert_eq!(measurement.low_ticks, 60);
+    }
+
+    #[test]
+    fn capture_before_overflow_polled_after_it() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        session.write(TIM_TCNT, u32::MAX - 5);
+        let mut capture = Capture::new(parts.ch7);
+        capture.start();
+        // The rising edge is latched before the wrap but only seen after it
+        session.with(|sim: &mut Simulator| {
+            sim.set_timer_input(7, true);
+            sim.step(20);
+        });
+        capture.poll();
+        session.with(|sim: &mut Simulator| sim.set_timer_input(7, false));
+        capture.poll();
+        session.with(|sim: &mut Simulator| {
+            sim.step(80);
+            sim.set_timer_input(7, true);
+        });
+        assert!(capture.poll());
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 100);
+        assert_eq!(measurement.high_ticks, 20);
+        assert_eq!(measurement.low_ticks, 80);
+    }
+
+    #[test]
+    fn wrap_between_capture_and_count_reads() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        session.write(TIM_TCNT, u32::MAX - 100);
+        let mut capture = Capture::new(parts.ch4);
+        capture.start();
+        cycle(&session, &mut capture, 30, 40);
+        session.with(|sim: &mut Simulator| sim.step(100));
+        // The counter wraps after the capture is read but before TCNT is
+        session.with(|sim: &mut Simulator| {
+            sim.set_timer_input(4, true);
+            sim.on_read(common::tim_tcn(4), Box::new(|sim: &mut Simulator| sim.step(50)));
+        });
+        assert!(capture.poll());
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 170);
+        assert_eq!(measurement.high_ticks, 30);
+        assert_eq!(measurement.low_ticks, 140);
+    }
+
+    #[test]
+    fn channels_of_one_timer_measure_independently() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        let mut first = Capture::new(parts.ch1);
+        let mut second = Capture::new(parts.ch3);
+        first.start();
+        second.start();
+        for _ in 0..2 {
+            cycle(&session, &mut first, 10, 30);
+            cycle(&session, &mut second, 60, 20);
+        }
+        // Each signal repeats every 120 ticks, one while the other is idle
+        assert_eq!(first.measurement().unwrap().period_ticks, 120);
+        assert_eq!(first.measurement().unwrap().high_ticks, 10);
+        assert_eq!(second.measurement().unwrap().period_ticks, 120);
+        assert_eq!(second.measurement().unwrap().high_ticks, 60);
+    }
+
+    #[test]
+    fn stopped_channel_ignores_edges() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = free_running(Pre::DIV1);
+        let mut capture = Capture::new(parts.ch1);
+        capture.start();
+        capture.stop();
+        cycle(&session, &mut capture, 10, 10);
+        assert!(!cycle(&session, &mut capture, 10, 10));
+        assert_eq!(capture.measurement(), None);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::ahb::clint::{CLINT, MTime};
+use crate::apb::timer::{CaptureEdge, InterruptMode, TIMChannel, Timeline};
+use crate::common::{self, Error};
+use crate::time::{Duration, Hertz};
+
+pub const FREQUENCY_DEFAULT_GATE_MS: u64 =    100;
+pub const FREQUENCY_DEFAULT_SWITCHOVER: u32 = 1_000;
+pub const MILLIHERTZ_PER_HERTZ: u64 =         1_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Strategy {
+    // Events in the gate divided by the CLINT gate time, best for fast signals
+    GateCount,
+    // Whole cycles between the first and last captured edge, best for slow signals
+    Reciprocal,
+    // Gate counting once a gate holds at least the switchover number of events
+    Auto,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrequencyReading {
+    // Computed from the captured edges only
+    pub millihertz: u64,
+    pub events: u32,
+    // The spacing of the captures shows edges were lost between two polls, so
+    // millihertz is too low and only bounds the input frequency from below
+    pub overrun: bool,
+    pub gate: Duration,
+    pub strategy: Strategy,
+}
+
+impl FrequencyReading {
+    pub fn frequency(&self) -> Hertz {
+        Hertz(((self.millihertz + MILLIHERTZ_PER_HERTZ / 2) / MILLIHERTZ_PER_HERTZ) as u32)
+    }
+}
+
+// Counts rising edges on a TIM input capture channel over gates timed by CLINT.
+// The counter must be free running (see TIMCounter::start_free_running).
+// Edges are taken from the capture flag, which holds one edge, so poll() must
+// run at least once per input cycle and at least once per counter wrap. The
+// highest frequency that can be measured is therefore the rate at which poll()
+// is called, not the timer clock. Faster inputs lose edges and read low. A
+// reading flags this as an overrun when a capture interval is a multiple of
+// the shortest one in the gate, which needs two edges polled back to back.
+pub struct FrequencyCounter {
+    ch: TIMChannel,
+    mtime: MTime,
+    timeline: Timeline,
+    strategy: Strategy,
+    switchover: u32,
+    gate: Duration,
+    gate_start: u64,
+    events: u32,
+    first: Option<u64>,
+    last: u64,
+    shortest: u64,
+}
+
+impl FrequencyCounter {
+    // Gates are timed by reading mtime, so the CLINT stays with its owner
+    pub fn new(ch: TIMChannel, clint: &CLINT) -> FrequencyCounter {
+        ch.set_input_capture(CaptureEdge::Rising, InterruptMode::Disabled);
+        ch.clear_interrupt();
+        let timeline: Timeline = Timeline::new(ch.read_count());
+        let mtime: MTime = clint.mtime();
+        let gate_start: u64 = mtime.now();
+        FrequencyCounter {
+            ch,
+            mtime,
+            timeline,
+            strategy: Strategy::Auto,
+            switchover: FREQUENCY_DEFAULT_SWITCHOVER,
+            gate: Duration::from_millis(FREQUENCY_DEFAULT_GATE_MS),
+            gate_start,
+            events: 0,
+            first: None,
+            last: 0,
+            shortest: u64::MAX,
+        }
+    }
+
+    pub fn set_gate(&mut self, gate: Duration) -> Result<(), Error> {
+        if gate.is_zero() {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        self.gate = gate;
+        self.restart();
+        Ok(())
+    }
+
+    pub fn get_gate(&self) -> Duration {
+        self.gate
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn get_strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    // Minimum events per gate before Auto switches from reciprocal to gate counting
+    pub fn set_switchover(&mut self, events: u32) {
+        self.switchover = events;
+    }
+
+    // Discards the current gate and opens a new one
+    pub fn restart(&mut self) {
+        self.ch.clear_interrupt();
+        self.gate_start = self.mtime.now();
+        self.events = 0;
+        self.first = None;
+        self.shortest = u64::MAX;
+    }
+
+    // Collects a pending edge and returns a reading when the gate has closed.
+    // The capture is read before the counter, as in Capture::poll().
+    pub fn poll(&mut self) -> Option<FrequencyReading> {
+        let mut value: Option<u32> = None;
+        if self.ch.interrupt_flag() {
+            value = Some(self.ch.read_input_capture());
+            self.ch.clear_interrupt();
+        }
+        self.timeline.update(self.ch.read_count());
+        if let Some(value) = value {
+            let time: u64 = self.timeline.extend(value);
+            if self.first.is_some() {
+                self.shortest = self.shortest.min(time - self.last);
+            }
+            self.events = self.events.saturating_add(1);
+            self.first.get_or_insert(time);
+            self.last = time;
+        }
+        let now: u64 = self.mtime.now();
+        let elapsed: u64 = now.wrapping_sub(self.gate_start);
+        if elapsed < self.gate.as_ticks() {
+            return None;
+        }
+        let reading: FrequencyReading = self.reading(elapsed);
+        self.gate_start = now;
+        self.events = 0;
+        self.first = None;
+        self.shortest = u64::MAX;
+        Some(reading)
+    }
+
+    pub fn channel(&self) -> &TIMChannel {
+        &self.ch
+    }
+
+    pub fn free(self) -> TIMChannel {
+        self.ch.set_input_capture_edge(CaptureEdge::Disabled);
+        self.ch.clear_interrupt();
+        self.ch
+    }
+
+    fn reading(&self, elapsed: u64) -> FrequencyReading {
+        let strategy: Strategy = match self.strategy {
+            Strategy::Auto if self.events < self.switchover && 2 <= self.events => Strategy::Reciprocal,
+            Strategy::Auto => Strategy::GateCount,
+            Strategy::Reciprocal if self.events < 2 => Strategy::GateCount,
+            strategy => strategy,
+        };
+        let millihertz: u64 = match strategy {
+            Strategy::Reciprocal => {
+                let span: u64 = self.last - self.first.unwrap_or(self.last);
+                let cycles: u64 = (self.events - 1) as u64;
+                scale(cycles, self.ch.count_frequency() as u64, span)
+            }
+            _ => scale(self.events as u64, common::CHIP_FREQ as u64, elapsed),
+        };
+        FrequencyReading {
+            millihertz,
+            events: self.events,
+            overrun: self.overrun(),
+            gate: Duration::from_ticks(elapsed),
+            strategy,
+        }
+    }
+
+    // More whole shortest intervals fit between the first and last capture than were captured
+    fn overrun(&self) -> bool {
+        if self.events < 2 || self.shortest == 0 {
+            return false;
+        }
+        let span: u64 = self.last - self.first.unwrap_or(self.last);
+        (span + self.shortest / 2) / self.shortest > (self.events - 1) as u64
+    }
+}
+
+// Events per tick at the given tick rate, in millihertz
+fn scale(events: u64, ticks_per_second: u64, ticks: u64) -> u64 {
+    if ticks == 0 { return 0; }
+    let millihertz: u128 = (events as u128 * ticks_per_second as u128 * MILLIHERTZ_PER_HERTZ as u128) / ticks as u128;
+    millihertz.min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apb::timer::{Pre, TIM, TIM_TCNT};
+    use crate::mock::{self, sim::Simulator};
+
+    fn prescaled_counter(session: &mock::Session, gate: Duration, pre_div: Pre) -> FrequencyCounter {
+        session.install(Simulator::new());
+        let mut parts = TIM::new().split();
+        parts.counter.set_prescaler(pre_div);
+        parts.counter.start_free_running();
+        let mut counter = FrequencyCounter::new(parts.ch3, &CLINT::new());
+        counter.set_gate(gate).unwrap();
+        counter
+    }
+
+    fn counter(session: &mock::Session, gate: Duration) -> FrequencyCounter {
+        prescaled_counter(session, gate, Pre::DIV1)
+    }
+
+    // Square wave on channel 3 with the given period in cycles, polled after every edge
+    fn drive(session: &mock::Session, counter: &mut FrequencyCounter, period: u64) -> FrequencyReading {
+        let mut high: bool = false;
+        loop {
+            session.with(|sim: &mut Simulator| sim.step(period / 2));
+            high = !high;
+            session.with(|sim: &mut Simulator| sim.set_timer_input(3, high));
+            if let Some(reading) = counter.poll() {
+                return reading;
+            }
+        }
+    }
+
+    #[test]
+    fn gate_count_on_fast_signal() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        counter.set_strategy(Strategy::GateCount);
+        let reading = drive(&session, &mut counter, 100);
+        assert_eq!(reading.strategy, Strategy::GateCount);
+        assert_eq!(reading.events, 100);
+        assert_eq!(reading.gate, Duration::from_micros(100));
+        assert_eq!(reading.frequency(), Hertz(1_000_000));
+    }
+
+    #[test]
+    fn reciprocal_on_slow_signal() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        counter.set_strategy(Strategy::Reciprocal);
+        // 100 MHz / 3000 cycles = 33333.333 Hz, only four edges per gate
+        let reading = drive(&session, &mut counter, 3_000);
+        assert_eq!(reading.strategy, Strategy::Reciprocal);
+        assert_eq!(reading.events, 4);
+        assert_eq!(reading.millihertz, 33_333_333);
+    }
+
+    #[test]
+    fn reciprocal_with_prescaler() {
+        let session = mock::session();
+        let mut counter = prescaled_counter(&session, Duration::from_micros(100), Pre::DIV8);
+        counter.set_strategy(Strategy::Reciprocal);
+        let reading = drive(&session, &mut counter, 4_000);
+        assert_eq!(reading.millihertz, 25_000_000);
+    }
+
+    #[test]
+    fn auto_switches_on_event_count() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        counter.set_switchover(50);
+        let reading = drive(&session, &mut counter, 3_000);
+        assert_eq!(reading.strategy, Strategy::Reciprocal);
+        assert_eq!(reading.millihertz, 33_333_333);
+        counter.restart();
+        let reading = drive(&session, &mut counter, 100);
+        assert_eq!(reading.strategy, Strategy::GateCount);
+        // Gate counting is only good to one event, here 1% of the reading
+        assert!((99..=100).contains(&reading.events));
+        assert!((990_000..=1_000_000).contains(&reading.frequency().0));
+    }
+
+    #[test]
+    fn too_few_edges_fall_back_to_gate_count() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(10));
+        counter.set_strategy(Strategy::Reciprocal);
+        let reading = drive(&session, &mut counter, 10_000);
+        assert_eq!(reading.strategy, Strategy::GateCount);
+        assert!(reading.events < 2);
+    }
+
+    // Square wave with a period of 100 cycles, polled after each of the first
+    // two rising edges and then only after every fourth one
+    fn drive_sparsely(session: &mock::Session, counter: &mut FrequencyCounter) -> FrequencyReading {
+        let mut polled: u32 = 0;
+        let mut skip: u32 = 0;
+        loop {
+            session.with(|sim: &mut Simulator| {
+                sim.step(50);
+                sim.set_timer_input(3, true);
+                sim.step(50);
+                sim.set_timer_input(3, false);
+            });
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            polled += 1;
+            if polled >= 2 {
+                skip = 3;
+            }
+            if let Some(reading) = counter.poll() {
+                return reading;
+            }
+        }
+    }
+
+    #[test]
+    fn edges_between_polls_are_an_overrun() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        counter.set_strategy(Strategy::Reciprocal);
+        // The 1 MHz input is only polled at 250 kHz after the first two edges
+        let reading = drive_sparsely(&session, &mut counter);
+        assert!(reading.overrun);
+        assert!(reading.events < 30);
+        assert!(reading.frequency().0 < 1_000_000);
+        counter.restart();
+        counter.set_strategy(Strategy::GateCount);
+        let reading = drive_sparsely(&session, &mut counter);
+        assert!(reading.overrun);
+        // Only captured edges count, over the gate as it was polled
+        assert_eq!(reading.millihertz, reading.events as u64 * 100_000_000_000 / reading.gate.as_ticks());
+        assert!(reading.frequency().0 < 300_000);
+    }
+
+    #[test]
+    fn edges_polled_in_time_are_not_an_overrun() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        let reading = drive(&session, &mut counter, 3_000);
+        assert!(!reading.overrun);
+    }
+
+    #[test]
+    fn wrap_between_capture_and_count_reads() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(100));
+        counter.set_strategy(Strategy::Reciprocal);
+        session.write(TIM_TCNT, u32::MAX - 10);
+        counter.restart();
+        // The first edge is latched just before the wrap and the counter wraps
+        // after poll() reads the capture but before it reads TCNT
+        session.with(|sim: &mut Simulator| {
+            sim.set_timer_input(3, true);
+            sim.on_read(common::tim_tcn(3), Box::new(|sim: &mut Simulator| {
+                sim.step(1_500);
+                sim.set_timer_input(3, false);
+            }));
+        });
+        assert_eq!(counter.poll(), None);
+        let reading = drive(&session, &mut counter, 3_000);
+        assert_eq!(reading.strategy, Strategy::Reciprocal);
+        assert_eq!(reading.millihertz, 33_333_333);
+    }
+
+    #[test]
+    fn zero_gate_is_rejected() {
+        let session = mock::session();
+        let mut counter = counter(&session, Duration::from_micros(10));
+        assert_eq!(counter.set_gate(Duration::ZERO), Err(Error::FrequencyOutOfRange));
+        assert_eq!(counter.get_gate(), Duration::from_micros(10));
+    }
+}
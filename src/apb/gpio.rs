@@ -1,8 +1,7 @@
-use crate::common::{U8_MAX};
-use volatile_register::{RW};
-
-// GPIO Construction Check
-pub static mut GPIO_CONSTRUCTED: bool =  false;
+use crate::peripherals::{self, AlreadyTaken};
+use crate::register::{self, Block, RW};
+use core::convert::Infallible;
+use core::marker::PhantomData;
 
 // GPIO Constants
 pub const GPIO: u32 =                  	0x80000000;
@@ -17,6 +16,7 @@ pub const GPIOALL_AFTX06: u32 =         0xFF;
 pub const GPIOALL: u32 =                0xFFFFFFFF;
 
 // GPIO Pins
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Pin {
     PIN0 = 1 << 0,
     PIN1 = 1 << 1,
@@ -28,13 +28,57 @@ pub enum Pin {
     PIN7 = 1 << 7,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Out {
     Lo,
     Hi,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Edge {
+    Disabled,
+    Rising,
+    Falling,
+    Either,
+}
+
 pub struct GPIO {
-    p: &'static mut GPIORegisterBlock
+    p: Block<GPIORegisterBlock>
+}
+
+// Pin Modes
+pub struct Input;
+pub struct Output;
+pub struct InterruptOnEdge;
+
+// Modes in which the pin level can be read
+pub trait InputMode {}
+impl InputMode for Input {}
+impl InputMode for InterruptOnEdge {}
+
+// Owned pin handed out by GPIO::split, with its direction in the type. Pins
+// share the GPIO registers and may live in different contexts, so every
+// read-modify-write they make runs inside a critical section.
+pub struct GPIOPin<const N: u8, MODE> {
+    p: Block<GPIORegisterBlock>,
+    _mode: PhantomData<MODE>
+}
+
+pub struct Parts {
+    pub pin0: GPIOPin<0, Input>,
+    pub pin1: GPIOPin<1, Input>,
+    pub pin2: GPIOPin<2, Input>,
+    pub pin3: GPIOPin<3, Input>,
+    pub pin4: GPIOPin<4, Input>,
+    pub pin5: GPIOPin<5, Input>,
+    pub pin6: GPIOPin<6, Input>,
+    pub pin7: GPIOPin<7, Input>,
+}
+
+// Single pin view of GPIO for use with embedded-hal drivers
+pub struct PinRef<'a> {
+    gpio: &'a mut GPIO,
+    pin: Pin
 }
 
 #[repr(C)]
@@ -48,18 +92,102 @@ struct GPIORegisterBlock {
     pub intr_sts: RW<u32>,
 }
 
+// Sets the edge bits of pins and enables their interrupts, unless Edge::Disabled
+fn configure_edges(p: &GPIORegisterBlock, pins: u32, edge: Edge) {
+    let (pos, neg): (bool, bool) = match edge {
+        Edge::Disabled => (false, false),
+        Edge::Rising =>   (true, false),
+        Edge::Falling =>  (false, true),
+        Edge::Either =>   (true, true),
+    };
+    unsafe {
+        let mut curr: u32 = p.intr_en.read();
+        curr &= !pins;
+        p.intr_en.write(curr);
+        curr = p.pos_edge.read();
+        if pos { curr |= pins; } else { curr &= !pins; }
+        p.pos_edge.write(curr);
+        curr = p.neg_edge.read();
+        if neg { curr |= pins; } else { curr &= !pins; }
+        p.neg_edge.write(curr);
+        if pos || neg {
+            curr = p.intr_en.read();
+            curr |= pins;
+            p.intr_en.write(curr);
+        }
+    }
+}
+
+// Stops edge on pins while keeping the interrupt enabled on pins that still
+// have the other edge, so Either becomes Rising or Falling rather than Disabled
+fn remove_edges(p: &GPIORegisterBlock, pins: u32, edge: Edge) {
+    let (pos, neg): (bool, bool) = match edge {
+        Edge::Disabled => (false, false),
+        Edge::Rising =>   (true, false),
+        Edge::Falling =>  (false, true),
+        Edge::Either =>   (true, true),
+    };
+    unsafe {
+        let mut pos_edge: u32 = p.pos_edge.read();
+        if pos { pos_edge &= !pins; }
+        let mut neg_edge: u32 = p.neg_edge.read();
+        if neg { neg_edge &= !pins; }
+        let mut curr: u32 = p.intr_en.read();
+        curr &= !(pins & !(pos_edge | neg_edge));
+        p.intr_en.write(curr);
+        p.pos_edge.write(pos_edge);
+        p.neg_edge.write(neg_edge);
+    }
+}
+
+fn read_edge(p: &GPIORegisterBlock, pin: u32) -> Edge {
+    if p.intr_en.read() & pin == 0 {
+        return Edge::Disabled;
+    }
+    match (p.pos_edge.read() & pin != 0, p.neg_edge.read() & pin != 0) {
+        (true, true) =>   Edge::Either,
+        (true, false) =>  Edge::Rising,
+        (false, true) =>  Edge::Falling,
+        (false, false) => Edge::Disabled,
+    }
+}
+
 impl GPIO {
     pub fn new() -> GPIO {
+        match GPIO::try_new() {
+            Ok(gpio) => gpio,
+            Err(_) =>  panic!("You may construct only one instance of GPIO."),
+        }
+    }
+
+    pub fn try_new() -> Result<GPIO, AlreadyTaken> {
+        if peripherals::claim(peripherals::GPIO_TAKEN) {
+            Ok(unsafe { GPIO::steal() })
+        }
+        else {
+            Err(AlreadyTaken)
+        }
+    }
+
+    // Returns the peripheral to its reset state so it can be constructed again
+    pub fn free(self) {
         unsafe {
-            if GPIO_CONSTRUCTED == false {
-                GPIO_CONSTRUCTED = true;
-                GPIO {
-                    p: &mut *(0x8000_0004 as *mut GPIORegisterBlock) 
-                }
-            }
-            else { 
-                panic!("You may construct only one instance of GPIO.")
-            }
+            self.p.intr_en.write(0);
+            self.p.pos_edge.write(0);
+            self.p.neg_edge.write(0);
+            self.p.intr_clr.write(GPIOALL_AFTX06);
+            self.p.data_dir.write(0);
+            self.p.data.write(0);
+        }
+        peripherals::release(peripherals::GPIO_TAKEN);
+    }
+
+    /// # Safety
+    /// Skips the ownership check. The caller must not race the owning instance,
+    /// e.g. by only using the stolen copy inside an interrupt handler.
+    pub unsafe fn steal() -> GPIO {
+        GPIO {
+            p: register::block(0x8000_0004)
         }
     }
 
@@ -82,13 +210,10 @@ impl GPIO {
     }
 
     pub fn enable_inputs(&mut self, pins: u8) {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
+        let pins: u32 = pins as u32;
         unsafe {
             let mut curr: u32 = self.p.data_dir.read();
-            curr &= !(pins as u32);
+            curr &= !pins;
             self.p.data_dir.write(curr);
         }
     }
@@ -107,10 +232,6 @@ impl GPIO {
     }
 
     pub fn read_inputs(&self, pins: u8) -> u32 {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
         self.p.data.read() & (pins as u32)
     }
 
@@ -132,17 +253,16 @@ impl GPIO {
     }
 
     pub fn enable_outputs(&mut self, pins: u8) {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
+        let pins: u32 = pins as u32;
         unsafe {
             let mut curr: u32 = self.p.data_dir.read();
-            curr |= pins as u32;
+            curr |= pins;
             self.p.data_dir.write(curr);
         }
     }
 
+    // Each arm spells out the bit it drives, zero included
+    #[allow(clippy::erasing_op, clippy::identity_op)]
     pub fn set_output(&mut self, pin: Pin, pin_output: Out) {
         unsafe {
             let mut curr: u32 = self.p.data.read();
@@ -209,14 +329,12 @@ impl GPIO {
     }
 
     pub fn set_outputs(&mut self, pins: u8, pin_outputs: u8) {
-        if (pins as u32) > (U8_MAX as u32) || (pin_outputs as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
+        let pins: u32 = pins as u32;
+        let pin_outputs: u32 = pin_outputs as u32;
         unsafe {
             let mut curr: u32 = self.p.data.read();
-            curr &= !(pins as u32);
-            curr |= (pins as u32) & (pin_outputs as u32);
+            curr &= !pins;
+            curr |= pins & pin_outputs;
             self.p.data.write(curr);
         }
     }
@@ -303,93 +421,76 @@ impl GPIO {
     }
 
     pub fn enable_interrupts_posedge(&mut self, pins: u8) {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
+        let pins: u32 = pins as u32;
         unsafe {
             let mut curr: u32 = self.p.neg_edge.read();
-            curr &= !(pins as u32);
+            curr &= !pins;
             self.p.neg_edge.write(curr);
             curr = self.p.pos_edge.read();
-            curr |= pins as u32;
+            curr |= pins;
             self.p.pos_edge.write(curr);
             curr = self.p.intr_en.read();
-            curr |= pins as u32;
+            curr |= pins;
             self.p.intr_en.write(curr);
         }
     }
 
     pub fn disable_interrupt_posedge(&mut self, pin: Pin) {
-        unsafe {
-            let mut curr: u32 = self.p.intr_en.read();
-            match pin {
-                Pin::PIN0 => {
-                    curr &= !(Pin::PIN0 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN0 as u32);
-                }
-                Pin::PIN1 => {
-                    curr &= !(Pin::PIN1 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN1 as u32);
-                }
-                Pin::PIN2 => {
-                    curr &= !(Pin::PIN2 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN2 as u32);
-                }
-                Pin::PIN3 => {
-                    curr &= !(Pin::PIN3 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN3 as u32);
-                }
-                Pin::PIN4 => {
-                    curr &= !(Pin::PIN4 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN4 as u32);
-                }
-                Pin::PIN5 => {
-                    curr &= !(Pin::PIN5 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN5 as u32);
-                }
-                Pin::PIN6 => {
-                    curr &= !(Pin::PIN6 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN6 as u32);
-                }
-                Pin::PIN7 => {
-                    curr &= !(Pin::PIN7 as u32);
-                    self.p.intr_en.write(curr);
-                    curr = self.p.pos_edge.read();
-                    curr &= !(Pin::PIN7 as u32);
-                }
-            }
-            self.p.pos_edge.write(curr);
-        }
+        remove_edges(&self.p, pin as u32, Edge::Rising);
     }
 
     pub fn disable_interrupts_posedge(&mut self, pins: u8) {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
-        unsafe {
-            let mut curr: u32 = self.p.intr_en.read();
-            curr &= !(pins as u32);
-            self.p.intr_en.write(curr);
-            curr = self.p.pos_edge.read();
-            curr &= !(pins as u32);
-            self.p.pos_edge.write(curr);
-        }
+        let pins: u32 = pins as u32;
+        remove_edges(&self.p, pins, Edge::Rising);
+    }
+
+    pub fn enable_interrupt_negedge(&mut self, pin: Pin) {
+        configure_edges(&self.p, pin as u32, Edge::Falling);
+    }
+
+    pub fn enable_interrupts_negedge(&mut self, pins: u8) {
+        let pins: u32 = pins as u32;
+        configure_edges(&self.p, pins, Edge::Falling);
+    }
+
+    pub fn disable_interrupt_negedge(&mut self, pin: Pin) {
+        remove_edges(&self.p, pin as u32, Edge::Falling);
+    }
+
+    pub fn disable_interrupts_negedge(&mut self, pins: u8) {
+        let pins: u32 = pins as u32;
+        remove_edges(&self.p, pins, Edge::Falling);
+    }
+
+    pub fn enable_interrupt_anyedge(&mut self, pin: Pin) {
+        configure_edges(&self.p, pin as u32, Edge::Either);
+    }
+
+    pub fn enable_interrupts_anyedge(&mut self, pins: u8) {
+        let pins: u32 = pins as u32;
+        configure_edges(&self.p, pins, Edge::Either);
+    }
+
+    pub fn disable_interrupt_anyedge(&mut self, pin: Pin) {
+        configure_edges(&self.p, pin as u32, Edge::Disabled);
+    }
+
+    pub fn disable_interrupts_anyedge(&mut self, pins: u8) {
+        let pins: u32 = pins as u32;
+        configure_edges(&self.p, pins, Edge::Disabled);
+    }
+
+    pub fn set_interrupt_edge(&mut self, pin: Pin, edge: Edge) {
+        configure_edges(&self.p, pin as u32, edge);
+    }
+
+    pub fn set_interrupts_edge(&mut self, pins: u8, edge: Edge) {
+        let pins: u32 = pins as u32;
+        configure_edges(&self.p, pins, edge);
+    }
+
+    pub fn interrupt_edge(&self, pin: Pin) -> Edge {
+        read_edge(&self.p, pin as u32)
     }
 
     pub fn clear_interrupt(&mut self, pin: Pin) {
@@ -410,13 +511,10 @@ impl GPIO {
     }
 
     pub fn clear_interrupts(&mut self, pins: u8) {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
+        let pins: u32 = pins as u32;
         unsafe {
             let mut curr: u32 = self.p.intr_clr.read();
-            curr |= pins as u32;
+            curr |= pins;
             self.p.intr_clr.write(curr);
         }
     }
@@ -435,10 +533,459 @@ impl GPIO {
     }
 
     pub fn interrupts_status(&self, pins: u8) -> u32 {
-        if (pins as u32) > (U8_MAX as u32)
-        {
-            panic!("Pins must be of type u8.")
-        }
         self.p.intr_sts.read() & (pins as u32)
     }
-}
\ No newline at end of file
+
+    pub fn pin(&mut self, pin: Pin) -> PinRef<'_> {
+        PinRef { gpio: self, pin }
+    }
+
+    // Consumes GPIO, resetting all pins to interrupt-free inputs
+    pub fn split(self) -> Parts {
+        unsafe {
+            let mut curr: u32 = self.p.intr_en.read();
+            curr &= !GPIOALL_AFTX06;
+            self.p.intr_en.write(curr);
+            curr = self.p.data_dir.read();
+            curr &= !GPIOALL_AFTX06;
+            self.p.data_dir.write(curr);
+        }
+        let p: Block<GPIORegisterBlock> = self.p;
+        Parts {
+            pin0: GPIOPin::new(p),
+            pin1: GPIOPin::new(p),
+            pin2: GPIOPin::new(p),
+            pin3: GPIOPin::new(p),
+            pin4: GPIOPin::new(p),
+            pin5: GPIOPin::new(p),
+            pin6: GPIOPin::new(p),
+            pin7: GPIOPin::new(p),
+        }
+    }
+}
+
+impl<const N: u8, MODE> GPIOPin<N, MODE> {
+    const MASK: u32 = 1 << N;
+
+    fn new(p: Block<GPIORegisterBlock>) -> GPIOPin<N, MODE> {
+        GPIOPin { p, _mode: PhantomData }
+    }
+
+    pub fn into_input(self) -> GPIOPin<N, Input> {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.intr_en.read();
+            curr &= !Self::MASK;
+            self.p.intr_en.write(curr);
+            curr = self.p.data_dir.read();
+            curr &= !Self::MASK;
+            self.p.data_dir.write(curr);
+        });
+        GPIOPin { p: self.p, _mode: PhantomData }
+    }
+
+    pub fn into_output(self) -> GPIOPin<N, Output> {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.intr_en.read();
+            curr &= !Self::MASK;
+            self.p.intr_en.write(curr);
+            curr = self.p.data_dir.read();
+            curr |= Self::MASK;
+            self.p.data_dir.write(curr);
+        });
+        GPIOPin { p: self.p, _mode: PhantomData }
+    }
+
+    pub fn into_interrupt_on_edge(self) -> GPIOPin<N, InterruptOnEdge> {
+        self.into_interrupt_on(Edge::Rising)
+    }
+
+    pub fn into_interrupt_on(self, edge: Edge) -> GPIOPin<N, InterruptOnEdge> {
+        critical_section::with(|_| {
+            unsafe {
+                let mut curr: u32 = self.p.data_dir.read();
+                curr &= !Self::MASK;
+                self.p.data_dir.write(curr);
+            }
+            configure_edges(&self.p, Self::MASK, edge);
+        });
+        GPIOPin { p: self.p, _mode: PhantomData }
+    }
+
+    pub fn pin_number(&self) -> u8 {
+        N
+    }
+}
+
+impl<const N: u8, MODE: InputMode> GPIOPin<N, MODE> {
+    pub fn is_high(&self) -> bool {
+        self.p.data.read() & Self::MASK != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+impl<const N: u8> GPIOPin<N, Output> {
+    pub fn set_high(&mut self) {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.data.read();
+            curr |= Self::MASK;
+            self.p.data.write(curr);
+        });
+    }
+
+    pub fn set_low(&mut self) {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.data.read();
+            curr &= !Self::MASK;
+            self.p.data.write(curr);
+        });
+    }
+
+    pub fn is_set_high(&self) -> bool {
+        self.p.data.read() & Self::MASK != 0
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+
+    pub fn toggle(&mut self) {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.data.read();
+            curr ^= Self::MASK;
+            self.p.data.write(curr);
+        });
+    }
+}
+
+impl<const N: u8> GPIOPin<N, InterruptOnEdge> {
+    pub fn set_edge(&mut self, edge: Edge) {
+        critical_section::with(|_| configure_edges(&self.p, Self::MASK, edge));
+    }
+
+    pub fn edge(&self) -> Edge {
+        read_edge(&self.p, Self::MASK)
+    }
+
+    pub fn clear_interrupt(&mut self) {
+        critical_section::with(|_| unsafe {
+            let mut curr: u32 = self.p.intr_clr.read();
+            curr |= Self::MASK;
+            self.p.intr_clr.write(curr);
+        });
+    }
+
+    pub fn interrupt_status(&self) -> bool {
+        self.p.intr_sts.read() & Self::MASK != 0
+    }
+}
+
+impl<'a> PinRef<'a> {
+    pub fn is_high(&self) -> bool {
+        self.gpio.read_input(self.pin) != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    pub fn set_high(&mut self) {
+        self.gpio.set_output(self.pin, Out::Hi);
+    }
+
+    pub fn set_low(&mut self) {
+        self.gpio.set_output(self.pin, Out::Lo);
+    }
+
+    pub fn toggle(&mut self) {
+        if self.is_high() { self.set_low(); } else { self.set_high(); }
+    }
+}
+
+impl<'a> embedded_hal::digital::ErrorType for PinRef<'a> {
+    type Error = Infallible;
+}
+
+impl<'a> embedded_hal::digital::OutputPin for PinRef<'a> {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        PinRef::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        PinRef::set_high(self);
+        Ok(())
+    }
+}
+
+impl<'a> embedded_hal::digital::StatefulOutputPin for PinRef<'a> {
+    fn is_set_high(&mut self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_low(self))
+    }
+}
+
+impl<'a> embedded_hal::digital::InputPin for PinRef<'a> {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_low(self))
+    }
+}
+
+impl<'a> embedded_hal_02::digital::v2::OutputPin for PinRef<'a> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        PinRef::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        PinRef::set_high(self);
+        Ok(())
+    }
+}
+
+impl<'a> embedded_hal_02::digital::v2::StatefulOutputPin for PinRef<'a> {
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_high(self))
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_low(self))
+    }
+}
+
+impl<'a> embedded_hal_02::digital::v2::ToggleableOutputPin for PinRef<'a> {
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        PinRef::toggle(self);
+        Ok(())
+    }
+}
+
+impl<'a> embedded_hal_02::digital::v2::InputPin for PinRef<'a> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(PinRef::is_low(self))
+    }
+}
+
+impl<const N: u8, MODE> embedded_hal::digital::ErrorType for GPIOPin<N, MODE> {
+    type Error = Infallible;
+}
+
+impl<const N: u8> embedded_hal::digital::OutputPin for GPIOPin<N, Output> {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        GPIOPin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        GPIOPin::set_high(self);
+        Ok(())
+    }
+}
+
+impl<const N: u8> embedded_hal::digital::StatefulOutputPin for GPIOPin<N, Output> {
+    fn is_set_high(&mut self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_set_low(self))
+    }
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        GPIOPin::toggle(self);
+        Ok(())
+    }
+}
+
+impl<const N: u8, MODE: InputMode> embedded_hal::digital::InputPin for GPIOPin<N, MODE> {
+    fn is_high(&mut self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_low(self))
+    }
+}
+
+impl<const N: u8> embedded_hal_02::digital::v2::OutputPin for GPIOPin<N, Output> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        GPIOPin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        GPIOPin::set_high(self);
+        Ok(())
+    }
+}
+
+impl<const N: u8> embedded_hal_02::digital::v2::StatefulOutputPin for GPIOPin<N, Output> {
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_set_high(self))
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_set_low(self))
+    }
+}
+
+impl<const N: u8> embedded_hal_02::digital::v2::ToggleableOutputPin for GPIOPin<N, Output> {
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        GPIOPin::toggle(self);
+        Ok(())
+    }
+}
+
+impl<const N: u8, MODE: InputMode> embedded_hal_02::digital::v2::InputPin for GPIOPin<N, MODE> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(GPIOPin::is_low(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use core::cell::RefCell;
+
+    #[test]
+    fn output_pins_drive_data() {
+        let session = mock::session();
+        let mut gpio = GPIO::new();
+        gpio.enable_output(Pin::PIN3);
+        gpio.set_output(Pin::PIN3, Out::Hi);
+        assert_eq!(session.read(GPIO_DATA_DIRECTION), 1 << 3);
+        assert_eq!(session.read(GPIO_DATA), 1 << 3);
+        gpio.set_output(Pin::PIN3, Out::Lo);
+        assert_eq!(session.read(GPIO_DATA), 0);
+    }
+
+    #[test]
+    fn inputs_read_data() {
+        let session = mock::session();
+        let gpio = GPIO::new();
+        session.write(GPIO_DATA, 0b1010_0000);
+        assert_eq!(gpio.read_input(Pin::PIN5), 1 << 5);
+        assert_eq!(gpio.read_input(Pin::PIN4), 0);
+        assert_eq!(gpio.read_inputs(0xF0), 0b1010_0000);
+    }
+
+    #[test]
+    fn pin_masks() {
+        let session = mock::session();
+        let mut gpio = GPIO::new();
+        gpio.enable_outputs(0x81);
+        assert_eq!(session.read(GPIO_DATA_DIRECTION), 0x81);
+        gpio.set_outputs(0x81, 0x01);
+        assert_eq!(session.read(GPIO_DATA), 0x01);
+        gpio.enable_inputs(0x01);
+        assert_eq!(session.read(GPIO_DATA_DIRECTION), 0x80);
+    }
+
+    #[test]
+    fn interrupt_edges() {
+        let session = mock::session();
+        let mut gpio = GPIO::new();
+        gpio.enable_interrupt_posedge(Pin::PIN0);
+        gpio.enable_interrupt_negedge(Pin::PIN1);
+        gpio.enable_interrupt_anyedge(Pin::PIN2);
+        assert_eq!(session.read(GPIO_POSITIVE_EDGE), 0b101);
+        assert_eq!(session.read(GPIO_NEGATIVE_EDGE), 0b110);
+        assert_eq!(session.read(GPIO_INTERRUPT_ENABLE), 0b111);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN0), Edge::Rising);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN1), Edge::Falling);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN2), Edge::Either);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN3), Edge::Disabled);
+        gpio.disable_interrupt_anyedge(Pin::PIN2);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN2), Edge::Disabled);
+    }
+
+    #[test]
+    fn disabling_one_edge_keeps_the_other() {
+        let session = mock::session();
+        let mut gpio = GPIO::new();
+        gpio.set_interrupt_edge(Pin::PIN2, Edge::Either);
+        gpio.disable_interrupt_negedge(Pin::PIN2);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN2), Edge::Rising);
+        assert_eq!(session.read(GPIO_INTERRUPT_ENABLE), Pin::PIN2 as u32);
+        assert_eq!(session.read(GPIO_NEGATIVE_EDGE), 0);
+        gpio.disable_interrupt_posedge(Pin::PIN2);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN2), Edge::Disabled);
+        assert_eq!(session.read(GPIO_INTERRUPT_ENABLE), 0);
+        gpio.set_interrupts_edge(0b0000_0011, Edge::Either);
+        gpio.set_interrupt_edge(Pin::PIN4, Edge::Falling);
+        gpio.disable_interrupts_posedge(0b0001_0011);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN0), Edge::Falling);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN1), Edge::Falling);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN4), Edge::Falling);
+        gpio.disable_interrupts_negedge(0b0000_0001);
+        assert_eq!(gpio.interrupt_edge(Pin::PIN0), Edge::Disabled);
+        assert_eq!(session.read(GPIO_INTERRUPT_ENABLE), 0b0001_0010);
+    }
+
+    #[test]
+    fn split_pins() {
+        let session = mock::session();
+        session.write(GPIO_DATA_DIRECTION, 0xFF);
+        let parts = GPIO::new().split();
+        assert_eq!(session.read(GPIO_DATA_DIRECTION), 0);
+        let mut led = parts.pin6.into_output();
+        led.set_high();
+        assert!(led.is_set_high());
+        led.toggle();
+        assert_eq!(session.read(GPIO_DATA), 0);
+        let button = parts.pin2.into_interrupt_on(Edge::Falling);
+        assert_eq!(button.edge(), Edge::Falling);
+        assert_eq!(session.read(GPIO_DATA_DIRECTION), 1 << 6);
+    }
+
+    static BUTTON: critical_section::Mutex<RefCell<Option<GPIOPin<2, InterruptOnEdge>>>> =
+        critical_section::Mutex::new(RefCell::new(None));
+
+    #[test]
+    fn pins_move_to_other_contexts() {
+        let session = mock::session();
+        let parts = GPIO::new().split();
+        let button = parts.pin2.into_interrupt_on(Edge::Rising);
+        critical_section::with(|cs| BUTTON.borrow_ref_mut(cs).replace(button));
+        let mut led = parts.pin6.into_output();
+        std::thread::spawn(move || led.set_high()).join().unwrap();
+        critical_section::with(|cs| {
+            let mut button = BUTTON.borrow_ref_mut(cs);
+            let button = button.as_mut().unwrap();
+            button.clear_interrupt();
+            assert_eq!(button.edge(), Edge::Rising);
+        });
+        assert_eq!(session.read(GPIO_DATA), 1 << 6);
+        assert_eq!(session.read(GPIO_INTERRUPT_CLEAR), 1 << 2);
+    }
+}
@@ -1,3 +1,6 @@
+pub mod capture;
+pub mod frequency;
 pub mod gpio;
 pub mod pwm;
-pub mod timer;
\ No newline at end of file
+pub mod timer;
+pub mod waveform;
\ No newline at end of file
@@ -1,11 +1,10 @@
-use crate::common;
-use volatile_register::{RW};
-
-// PWM Construction Check
-pub static mut PWM_CONSTRUCTED: bool =   false;
+use crate::common::{self, Error};
+use crate::peripherals::{self, AlreadyTaken};
+use crate::register::{self, Block, RW};
 
 // PWM Constants
 pub const PWM: u32 =                      0x80010000;
+#[allow(clippy::identity_op)]
 pub const PWM_PERIOD: u32 =               PWM + 0x00;
 pub const PWM_DUTY: u32 =                 PWM + 0x04;
 pub const PWM_CONTROL: u32 =              PWM + 0x08;
@@ -17,136 +16,961 @@ pub const PWM_CONTROL_ALIGN_LEFT: u32 =   !(1 << 2);
 pub const PWM_CONTROL_ALIGN_CENTER: u32 = 1 << 2;
 pub const PWM_CHANNEL_SIZE: u32 =         0x0C;
 pub const PWM_MAX_FREQ: u32 =             common::CHIP_FREQ / 2;
+pub const PWM_MIN_PERIOD: u32 =           2;
 pub const AFTX06_DUTY_OFFSET: u32 =       1;
 
+// Channel count of the chip variant, each channel occupies PWM_CHANNEL_SIZE bytes
+#[cfg(not(feature = "pwm-2ch"))]
+pub const PWM_CHANNELS: usize =           1;
+#[cfg(all(feature = "pwm-2ch", not(feature = "pwm-4ch")))]
+pub const PWM_CHANNELS: usize =           2;
+#[cfg(all(feature = "pwm-4ch", not(feature = "pwm-8ch")))]
+pub const PWM_CHANNELS: usize =           4;
+#[cfg(feature = "pwm-8ch")]
+pub const PWM_CHANNELS: usize =           8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Channel {
     CH0,
+    #[cfg(feature = "pwm-2ch")]
+    CH1,
+    #[cfg(feature = "pwm-4ch")]
+    CH2,
+    #[cfg(feature = "pwm-4ch")]
+    CH3,
+    #[cfg(feature = "pwm-8ch")]
+    CH4,
+    #[cfg(feature = "pwm-8ch")]
+    CH5,
+    #[cfg(feature = "pwm-8ch")]
+    CH6,
+    #[cfg(feature = "pwm-8ch")]
+    CH7,
+}
+
+impl Channel {
+    pub fn from_index(index: usize) -> Option<Channel> {
+        match index {
+            0 => Some(Channel::CH0),
+            #[cfg(feature = "pwm-2ch")]
+            1 => Some(Channel::CH1),
+            #[cfg(feature = "pwm-4ch")]
+            2 => Some(Channel::CH2),
+            #[cfg(feature = "pwm-4ch")]
+            3 => Some(Channel::CH3),
+            #[cfg(feature = "pwm-8ch")]
+            4 => Some(Channel::CH4),
+            #[cfg(feature = "pwm-8ch")]
+            5 => Some(Channel::CH5),
+            #[cfg(feature = "pwm-8ch")]
+            6 => Some(Channel::CH6),
+            #[cfg(feature = "pwm-8ch")]
+            7 => Some(Channel::CH7),
+            _ => None,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Alignment {
+    Left,
+    Center,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Duty {
+    // CHIP_FREQ cycles, excluding AFTX06_DUTY_OFFSET
+    Cycles(u32),
+    // Share of whatever period the config ends up with
+    Percent(u8),
+}
+
+// Period in CHIP_FREQ cycles, or the frequency no period could be derived from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Period {
+    Cycles(u32),
+    BadFrequency(u32),
+}
+
+// Channel settings built up with the methods below. A frequency is turned
+// into a period as soon as it is given and a duty percent is kept until
+// validate() or configure() resolves it against the final period. Two configs
+// are equal when they write the same registers, however they were built.
+#[derive(Clone, Copy, Debug)]
+pub struct PwmConfig {
+    period: Period,
+    duty: Duty,
+    enabled: bool,
+    polarity: Polarity,
+    alignment: Alignment,
+}
+
+impl Default for PwmConfig {
+    fn default() -> PwmConfig {
+        PwmConfig::new()
+    }
+}
+
+impl PartialEq for PwmConfig {
+    fn eq(&self, other: &PwmConfig) -> bool {
+        match (self.resolve(), other.resolve()) {
+            (Ok(ours), Ok(theirs)) => ours == theirs && self.control() == other.control(),
+            _ => {
+                self.period == other.period && self.duty == other.duty && self.enabled == other.enabled
+                    && self.polarity == other.polarity && self.alignment == other.alignment
+            }
+        }
+    }
+}
+
+impl Eq for PwmConfig {}
+
+impl PwmConfig {
+    // Starts disabled, active high and left aligned with no period set
+    pub fn new() -> PwmConfig {
+        PwmConfig {
+            period: Period::Cycles(0),
+            duty: Duty::Cycles(0),
+            enabled: false,
+            polarity: Polarity::ActiveHigh,
+            alignment: Alignment::Left,
+        }
+    }
+
+    // Frequencies of 0 or above PWM_MAX_FREQ are kept and fail validate()
+    pub fn frequency(mut self, frequency: u32) -> PwmConfig {
+        self.period = if frequency == 0 || PWM_MAX_FREQ < frequency {
+            Period::BadFrequency(frequency)
+        }
+        else {
+            Period::Cycles(common::rounding_division(common::CHIP_FREQ, frequency))
+        };
+        self
+    }
+
+    pub fn period(mut self, period: u32) -> PwmConfig {
+        self.period = Period::Cycles(period);
+        self
+    }
+
+    pub fn duty(mut self, duty: u32) -> PwmConfig {
+        self.duty = Duty::Cycles(duty);
+        self
+    }
+
+    // Resolved against the final period, so it may come before frequency() or period()
+    pub fn duty_percent(mut self, percent: u8) -> PwmConfig {
+        self.duty = Duty::Percent(percent);
+        self
+    }
+
+    pub fn polarity(mut self, polarity: Polarity) -> PwmConfig {
+        self.polarity = polarity;
+        self
+    }
+
+    pub fn alignment(mut self, alignment: Alignment) -> PwmConfig {
+        self.alignment = alignment;
+        self
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> PwmConfig {
+        self.enabled = enabled;
+        self
+    }
+
+    // Period in CHIP_FREQ cycles, None after an out of range frequency
+    pub fn get_period(&self) -> Option<u32> {
+        match self.period {
+            Period::Cycles(period) => Some(period),
+            Period::BadFrequency(_) => None,
+        }
+    }
+
+    pub fn get_duty(&self) -> Duty {
+        self.duty
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn get_polarity(&self) -> Polarity {
+        self.polarity
+    }
+
+    pub fn get_alignment(&self) -> Alignment {
+        self.alignment
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.resolve().map(|_| ())
+    }
+
+    // Period and duty to write, with any duty percent applied to the period
+    fn resolve(&self) -> Result<(u32, u32), Error> {
+        let period: u32 = match self.period {
+            Period::Cycles(period) if period < PWM_MIN_PERIOD => return Err(Error::InvalidPeriod),
+            Period::Cycles(period) => period,
+            Period::BadFrequency(_) => return Err(Error::FrequencyOutOfRange),
+        };
+        let duty: u32 = match self.duty {
+            Duty::Percent(percent) if percent > 100 => return Err(Error::DutyOutOfRange),
+            Duty::Percent(percent) => ((period as u64 * percent as u64 + 50) / 100) as u32,
+            Duty::Cycles(duty) => duty,
+        };
+        if duty > period {
+            return Err(Error::DutyOutOfRange);
+        }
+        Ok((period, duty))
+    }
+
+    fn control(&self) -> u32 {
+        let mut ctrl: u32 = 0;
+        if self.enabled { ctrl |= PWM_CONTROL_ENABLE; }
+        match self.polarity {
+            Polarity::ActiveHigh => (),
+            Polarity::ActiveLow =>  ctrl |= PWM_CONTROL_ACTIVE_LOW,
+        }
+        match self.alignment {
+            Alignment::Left =>   (),
+            Alignment::Center => ctrl |= PWM_CONTROL_ALIGN_CENTER,
+        }
+        ctrl
+    }
 }
 
 pub struct PWM {
-    p: &'static mut PWMRegisterBlock
+    p: Block<PWMRegisterBlock>
+}
+
+// One channel of a split PWM. Every channel has its own period, duty and
+// control registers, so the handles never touch each other's state.
+pub struct PWMChannel {
+    p: Block<PWMRegisterBlock>,
+    channel: Channel
+}
+
+pub struct Parts {
+    pub ch0: PWMChannel,
+    #[cfg(feature = "pwm-2ch")]
+    pub ch1: PWMChannel,
+    #[cfg(feature = "pwm-4ch")]
+    pub ch2: PWMChannel,
+    #[cfg(feature = "pwm-4ch")]
+    pub ch3: PWMChannel,
+    #[cfg(feature = "pwm-8ch")]
+    pub ch4: PWMChannel,
+    #[cfg(feature = "pwm-8ch")]
+    pub ch5: PWMChannel,
+    #[cfg(feature = "pwm-8ch")]
+    pub ch6: PWMChannel,
+    #[cfg(feature = "pwm-8ch")]
+    pub ch7: PWMChannel,
 }
 
 #[repr(C)]
 struct PWMRegisterBlock {
-    pub pwm0_period: RW<u32>,
-    pub pwm0_duty:   RW<u32>,
-    pub pwm0_ctrl:   RW<u32>,
+    pub channels: [PWMChannelBlock; PWM_CHANNELS],
+}
+
+// One channel's registers, common::pwmn() bytes from the start of the block
+#[repr(C)]
+struct PWMChannelBlock {
+    pub period: RW<u32>,
+    pub duty:   RW<u32>,
+    pub ctrl:   RW<u32>,
 }
 
 impl PWM {
     pub fn new() -> PWM {
-        unsafe {
-            if PWM_CONSTRUCTED == false {
-                PWM_CONSTRUCTED = true;
-                PWM {
-                    p: &mut *(0x8001_0000 as *mut PWMRegisterBlock) 
-                }
-            }
-            else { 
-                panic!("You may construct only one instance of PWM.")
+        match PWM::try_new() {
+            Ok(pwm) => pwm,
+            Err(_) => panic!("You may construct only one instance of PWM."),
+        }
+    }
+
+    pub fn try_new() -> Result<PWM, AlreadyTaken> {
+        if peripherals::claim(peripherals::PWM_TAKEN) {
+            Ok(unsafe { PWM::steal() })
+        }
+        else {
+            Err(AlreadyTaken)
+        }
+    }
+
+    // Returns the peripheral to its reset state so it can be constructed again
+    pub fn free(self) {
+        for ch in self.p.channels.iter() {
+            unsafe {
+                ch.ctrl.write(0);
+                ch.period.write(0);
+                ch.duty.write(0);
             }
         }
+        peripherals::release(peripherals::PWM_TAKEN);
+    }
+
+    /// # Safety
+    /// Skips the ownership check. The caller must not race the owning instance,
+    /// e.g. by only using the stolen copy inside an interrupt handler.
+    pub unsafe fn steal() -> PWM {
+        PWM {
+            p: register::block(0x8001_0000)
+        }
     }
 
-    pub fn set_frequency(&mut self, channel: Channel, mut frequency: u32) {
+    pub fn set_frequency(&mut self, channel: Channel, frequency: u32) -> Result<(), Error> {
+        if frequency == 0 || PWM_MAX_FREQ < frequency {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            if PWM_MAX_FREQ < frequency { frequency = PWM_MAX_FREQ; }
             let period: u32 = common::rounding_division(common::CHIP_FREQ, frequency);
-            match channel {
-                Channel::CH0 => {
-                    self.p.pwm0_period.write(period);
-                    self.p.pwm0_duty.write(common::rounding_division(period, 2) + AFTX06_DUTY_OFFSET);
-                }
-            }
+            ch.period.write(period);
+            ch.duty.write(common::rounding_division(period, 2) + AFTX06_DUTY_OFFSET);
         }
+        Ok(())
     }
 
-    pub fn set_period(&mut self, channel: Channel, period: u32) {
+    pub fn set_period(&mut self, channel: Channel, period: u32) -> Result<(), Error> {
+        if period < PWM_MIN_PERIOD {
+            return Err(Error::InvalidPeriod);
+        }
         unsafe {
-            match channel {
-                Channel::CH0 => self.p.pwm0_period.write(period),
-            }
+            self.block(channel).period.write(period);
         }
+        Ok(())
     }
 
-    pub fn set_duty(&mut self, channel: Channel, duty: u32) {
+    pub fn set_duty(&mut self, channel: Channel, duty: u32) -> Result<(), Error> {
+        let ch: &PWMChannelBlock = self.block(channel);
+        if duty > ch.period.read() {
+            return Err(Error::DutyOutOfRange);
+        }
         unsafe {
-            match channel {
-                Channel::CH0 => self.p.pwm0_duty.write(duty + AFTX06_DUTY_OFFSET),
+            ch.duty.write(duty + AFTX06_DUTY_OFFSET);
+        }
+        Ok(())
+    }
+
+    pub fn set_duty_percent(&mut self, channel: Channel, percent: u8) -> Result<(), Error> {
+        self.set_duty_fraction(channel, percent as u32, 100)
+    }
+
+    // Duty of num / den of the current period, rounded to the nearest cycle
+    pub fn set_duty_fraction(&mut self, channel: Channel, num: u32, den: u32) -> Result<(), Error> {
+        if den == 0 || num > den {
+            return Err(Error::DutyOutOfRange);
+        }
+        let period: u64 = self.get_period(channel) as u64;
+        let duty: u64 = (period * num as u64 + den as u64 / 2) / den as u64;
+        self.set_duty(channel, duty as u32)
+    }
+
+    pub fn get_period(&self, channel: Channel) -> u32 {
+        self.block(channel).period.read()
+    }
+
+    pub fn get_duty(&self, channel: Channel) -> u32 {
+        self.block(channel).duty.read().saturating_sub(AFTX06_DUTY_OFFSET)
+    }
+
+    pub fn get_config(&self, channel: Channel) -> PwmConfig {
+        let ctrl: u32 = self.block(channel).ctrl.read();
+        PwmConfig {
+            period: Period::Cycles(self.get_period(channel)),
+            duty: Duty::Cycles(self.get_duty(channel)),
+            enabled: ctrl & PWM_CONTROL_ENABLE != 0,
+            polarity: if ctrl & PWM_CONTROL_ACTIVE_LOW != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            alignment: if ctrl & PWM_CONTROL_ALIGN_CENTER != 0 { Alignment::Center } else { Alignment::Left },
+        }
+    }
+
+    // Validates the whole config first, then disables the channel, writes period
+    // and duty and sets every control bit with a single final write
+    pub fn configure(&mut self, channel: Channel, config: &PwmConfig) -> Result<(), Error> {
+        let (period, duty): (u32, u32) = config.resolve()?;
+        let ch: &PWMChannelBlock = self.block(channel);
+        unsafe {
+            let curr: u32 = ch.ctrl.read();
+            if curr & PWM_CONTROL_ENABLE != 0 {
+                ch.ctrl.write(curr & PWM_CONTROL_DISABLE);
             }
+            ch.period.write(period);
+            ch.duty.write(duty + AFTX06_DUTY_OFFSET);
+            ch.ctrl.write(config.control());
+        }
+        Ok(())
+    }
+
+    // Consumes PWM so each channel can go to its own driver
+    pub fn split(self) -> Parts {
+        let p: Block<PWMRegisterBlock> = self.p;
+        Parts {
+            ch0: PWMChannel { p, channel: Channel::CH0 },
+            #[cfg(feature = "pwm-2ch")]
+            ch1: PWMChannel { p, channel: Channel::CH1 },
+            #[cfg(feature = "pwm-4ch")]
+            ch2: PWMChannel { p, channel: Channel::CH2 },
+            #[cfg(feature = "pwm-4ch")]
+            ch3: PWMChannel { p, channel: Channel::CH3 },
+            #[cfg(feature = "pwm-8ch")]
+            ch4: PWMChannel { p, channel: Channel::CH4 },
+            #[cfg(feature = "pwm-8ch")]
+            ch5: PWMChannel { p, channel: Channel::CH5 },
+            #[cfg(feature = "pwm-8ch")]
+            ch6: PWMChannel { p, channel: Channel::CH6 },
+            #[cfg(feature = "pwm-8ch")]
+            ch7: PWMChannel { p, channel: Channel::CH7 },
         }
     }
 
     pub fn disable(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr &= PWM_CONTROL_DISABLE;
-                    self.p.pwm0_ctrl.write(curr);
-                }
-            }
+            let mut curr: u32 = ch.ctrl.read();
+            curr &= PWM_CONTROL_DISABLE;
+            ch.ctrl.write(curr);
         }
     }
 
     pub fn enable(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr |= PWM_CONTROL_ENABLE;
-                    self.p.pwm0_ctrl.write(curr);
-                }
-            }
+            let mut curr: u32 = ch.ctrl.read();
+            curr |= PWM_CONTROL_ENABLE;
+            ch.ctrl.write(curr);
         }
     }
 
     pub fn set_active_high(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr &= PWM_CONTROL_ACTIVE_HIGH;
-                    self.p.pwm0_ctrl.write(curr);
-                }
-            }
+            let mut curr: u32 = ch.ctrl.read();
+            curr &= PWM_CONTROL_ACTIVE_HIGH;
+            ch.ctrl.write(curr);
         }
     }
 
     pub fn set_active_low(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr |= PWM_CONTROL_ACTIVE_LOW;
-                    self.p.pwm0_ctrl.write(curr);
-                }
-            }
+            let mut curr: u32 = ch.ctrl.read();
+            curr |= PWM_CONTROL_ACTIVE_LOW;
+            ch.ctrl.write(curr);
         }
     }
 
     pub fn set_align_left(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr &= PWM_CONTROL_ALIGN_LEFT;
-                    self.p.pwm0_ctrl.write(curr);
-                }
-            }
+            let mut curr: u32 = ch.ctrl.read();
+            curr &= PWM_CONTROL_ALIGN_LEFT;
+            ch.ctrl.write(curr);
         }
     }
 
     pub fn set_align_center(&mut self, channel: Channel) {
+        let ch: &PWMChannelBlock = self.block(channel);
         unsafe {
-            match channel {
-                Channel::CH0 => {
-                    let mut curr: u32 = self.p.pwm0_ctrl.read();
-                    curr |= PWM_CONTROL_ALIGN_CENTER;
-                    self.p.pwm0_ctrl.write(curr);
+            let mut curr: u32 = ch.ctrl.read();
+            curr |= PWM_CONTROL_ALIGN_CENTER;
+            ch.ctrl.write(curr);
+        }
+    }
+
+    fn block(&self, channel: Channel) -> &PWMChannelBlock {
+        &self.p.channels[channel.index()]
+    }
+}
+
+impl PWMChannel {
+    // The handle shares PWM's register code through a view that is never freed
+    fn pwm(&self) -> PWM {
+        PWM { p: self.p }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn set_frequency(&mut self, frequency: u32) -> Result<(), Error> {
+        self.pwm().set_frequency(self.channel, frequency)
+    }
+
+    pub fn set_period(&mut self, period: u32) -> Result<(), Error> {
+        self.pwm().set_period(self.channel, period)
+    }
+
+    pub fn set_duty(&mut self, duty: u32) -> Result<(), Error> {
+        self.pwm().set_duty(self.channel, duty)
+    }
+
+    pub fn set_duty_percent(&mut self, percent: u8) -> Result<(), Error> {
+        self.pwm().set_duty_percent(self.channel, percent)
+    }
+
+    pub fn set_duty_fraction(&mut self, num: u32, den: u32) -> Result<(), Error> {
+        self.pwm().set_duty_fraction(self.channel, num, den)
+    }
+
+    pub fn get_period(&self) -> u32 {
+        self.pwm().get_period(self.channel)
+    }
+
+    pub fn get_duty(&self) -> u32 {
+        self.pwm().get_duty(self.channel)
+    }
+
+    pub fn get_config(&self) -> PwmConfig {
+        self.pwm().get_config(self.channel)
+    }
+
+    pub fn configure(&mut self, config: &PwmConfig) -> Result<(), Error> {
+        self.pwm().configure(self.channel, config)
+    }
+
+    pub fn disable(&mut self) {
+        self.pwm().disable(self.channel);
+    }
+
+    pub fn enable(&mut self) {
+        self.pwm().enable(self.channel);
+    }
+
+    pub fn set_active_high(&mut self) {
+        self.pwm().set_active_high(self.channel);
+    }
+
+    pub fn set_active_low(&mut self) {
+        self.pwm().set_active_low(self.channel);
+    }
+
+    pub fn set_align_left(&mut self) {
+        self.pwm().set_align_left(self.channel);
+    }
+
+    pub fn set_align_center(&mut self) {
+        self.pwm().set_align_center(self.channel);
+    }
+
+    // Duty resolution offered to embedded-hal, the period capped to u16
+    pub fn max_duty_cycle(&self) -> u16 {
+        self.get_period().clamp(1, u16::MAX as u32) as u16
+    }
+}
+
+impl embedded_hal::pwm::ErrorType for PWMChannel {
+    type Error = Error;
+}
+
+impl embedded_hal::pwm::SetDutyCycle for PWMChannel {
+    fn max_duty_cycle(&self) -> u16 {
+        PWMChannel::max_duty_cycle(self)
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Error> {
+        let max: u16 = PWMChannel::max_duty_cycle(self);
+        self.set_duty_fraction(duty as u32, max as u32)
+    }
+}
+
+impl embedded_hal_02::PwmPin for PWMChannel {
+    type Duty = u32;
+
+    fn disable(&mut self) {
+        PWMChannel::disable(self);
+    }
+
+    fn enable(&mut self) {
+        PWMChannel::enable(self);
+    }
+
+    fn get_duty(&self) -> u32 {
+        PWMChannel::get_duty(self)
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        self.get_period()
+    }
+
+    // Duties past the period are clamped to fully on
+    fn set_duty(&mut self, duty: u32) {
+        let period: u32 = self.get_period();
+        let _ = PWMChannel::set_duty(self, duty.min(period));
+    }
+}
+
+// The legacy trait has one period for all channels, taken from CH0 and
+// written to every channel. Time and Duty are in CHIP_FREQ cycles.
+impl embedded_hal_02::Pwm for PWM {
+    type Channel = Channel;
+    type Time = u32;
+    type Duty = u32;
+
+    fn disable(&mut self, channel: Channel) {
+        PWM::disable(self, channel);
+    }
+
+    fn enable(&mut self, channel: Channel) {
+        PWM::enable(self, channel);
+    }
+
+    fn get_period(&self) -> u32 {
+        PWM::get_period(self, Channel::CH0)
+    }
+
+    fn get_duty(&self, channel: Channel) -> u32 {
+        PWM::get_duty(self, channel)
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        PWM::get_period(self, Channel::CH0)
+    }
+
+    fn set_duty(&mut self, channel: Channel, duty: u32) {
+        let period: u32 = PWM::get_period(self, channel);
+        let _ = PWM::set_duty(self, channel, duty.min(period));
+    }
+
+    // Periods below PWM_MIN_PERIOD are raised to it. A duty longer than the new
+    // period is cut to the period first, so get_duty() never exceeds get_max_duty().
+    fn set_period<P>(&mut self, period: P)
+    where
+        P: Into<u32>
+    {
+        let period: u32 = period.into().max(PWM_MIN_PERIOD);
+        for index in 0..PWM_CHANNELS {
+            if let Some(channel) = Channel::from_index(index) {
+                if PWM::get_duty(self, channel) > period {
+                    let _ = PWM::set_duty(self, channel, period);
                 }
+                let _ = PWM::set_period(self, channel, period);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, Backend, Memory};
+
+    // Register file that also keeps every write in order
+    #[derive(Default)]
+    struct Recorder {
+        memory: Memory,
+        writes: Vec<(u32, u32)>,
+    }
+
+    impl Backend for Recorder {
+        fn read(&mut self, address: u32) -> u32 {
+            self.memory.read(address)
+        }
+
+        fn write(&mut self, address: u32, value: u32) {
+            self.writes.push((address, value));
+            self.memory.write(address, value);
+        }
+    }
+
+    #[test]
+    fn frequency_sets_period_and_half_duty() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        pwm.set_frequency(Channel::CH0, 1_000_000).unwrap();
+        assert_eq!(session.read(PWM_PERIOD), 100);
+        assert_eq!(session.read(PWM_DUTY), 50 + AFTX06_DUTY_OFFSET);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        assert_eq!(pwm.set_frequency(Channel::CH0, PWM_MAX_FREQ + 1), Err(Error::FrequencyOutOfRange));
+        assert_eq!(pwm.set_frequency(Channel::CH0, 0), Err(Error::FrequencyOutOfRange));
+        assert_eq!(pwm.set_period(Channel::CH0, 1), Err(Error::InvalidPeriod));
+        pwm.set_period(Channel::CH0, 100).unwrap();
+        assert_eq!(pwm.set_duty(Channel::CH0, 101), Err(Error::DutyOutOfRange));
+        assert_eq!(session.read(PWM_DUTY), 0);
+    }
+
+    #[test]
+    fn control_bits() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        pwm.enable(Channel::CH0);
+        pwm.set_active_low(Channel::CH0);
+        pwm.set_align_center(Channel::CH0);
+        assert_eq!(session.read(PWM_CONTROL), 0b111);
+        pwm.set_active_high(Channel::CH0);
+        pwm.disable(Channel::CH0);
+        assert_eq!(session.read(PWM_CONTROL), 0b100);
+    }
+
+    #[test]
+    fn channels_use_pwmn_stride() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        for index in 0..PWM_CHANNELS {
+            let channel = Channel::from_index(index).unwrap();
+            assert_eq!(channel.index(), index);
+            let offset: u32 = common::pwmn(index as u32);
+            pwm.set_period(channel, 100 + index as u32).unwrap();
+            pwm.set_duty(channel, 10 + index as u32).unwrap();
+            pwm.enable(channel);
+            assert_eq!(session.read(PWM_PERIOD + offset), 100 + index as u32);
+            assert_eq!(session.read(PWM_DUTY + offset), 10 + index as u32 + AFTX06_DUTY_OFFSET);
+            assert_eq!(session.read(PWM_CONTROL + offset), PWM_CONTROL_ENABLE);
+        }
+        assert_eq!(Channel::from_index(PWM_CHANNELS), None);
+        assert_eq!(core::mem::size_of::<PWMChannelBlock>() as u32, PWM_CHANNEL_SIZE);
+    }
+
+    #[test]
+    fn split_channels_are_independent() {
+        use embedded_hal::pwm::SetDutyCycle;
+        let session = mock::session();
+        let parts = PWM::new().split();
+        let mut first = parts.ch0;
+        first.set_period(1_000).unwrap();
+        first.enable();
+        #[cfg(feature = "pwm-2ch")]
+        {
+            let mut second = parts.ch1;
+            second.set_period(400).unwrap();
+            second.set_active_low();
+            // Both handles are live at once
+            first.set_duty_cycle_percent(25).unwrap();
+            second.set_duty_cycle_percent(75).unwrap();
+            assert_eq!(second.channel(), Channel::CH1);
+            assert_eq!(session.read(PWM_DUTY + common::pwmn(1)), 300 + AFTX06_DUTY_OFFSET);
+            assert_eq!(session.read(PWM_CONTROL + common::pwmn(1)), PWM_CONTROL_ACTIVE_LOW);
+        }
+        first.set_duty_cycle_percent(25).unwrap();
+        assert_eq!(first.get_duty(), 250);
+        assert_eq!(session.read(PWM_CONTROL), PWM_CONTROL_ENABLE);
+        // A handle can be given to another context, such as an interrupt handler
+        std::thread::spawn(move || first.set_duty_cycle_fully_on().unwrap()).join().unwrap();
+        assert_eq!(session.read(PWM_DUTY), 1_000 + AFTX06_DUTY_OFFSET);
+    }
+
+    #[test]
+    fn free_resets_every_channel() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        for index in 0..PWM_CHANNELS {
+            let channel = Channel::from_index(index).unwrap();
+            pwm.set_frequency(channel, 1_000).unwrap();
+            pwm.enable(channel);
+        }
+        pwm.free();
+        for index in 0..PWM_CHANNELS {
+            let offset: u32 = common::pwmn(index as u32);
+            assert_eq!(session.read(PWM_PERIOD + offset), 0);
+            assert_eq!(session.read(PWM_CONTROL + offset), 0);
+        }
+    }
+
+    #[test]
+    fn duty_in_percent_and_fraction() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        pwm.set_frequency(Channel::CH0, 1_000).unwrap();
+        pwm.set_duty_percent(Channel::CH0, 25).unwrap();
+        assert_eq!(session.read(PWM_DUTY), 25_000 + AFTX06_DUTY_OFFSET);
+        assert_eq!(pwm.get_duty(Channel::CH0), 25_000);
+        pwm.set_duty_fraction(Channel::CH0, 1, 3).unwrap();
+        assert_eq!(pwm.get_duty(Channel::CH0), 33_333);
+        pwm.set_duty_fraction(Channel::CH0, 2, 3).unwrap();
+        assert_eq!(pwm.get_duty(Channel::CH0), 66_667);
+        pwm.set_duty_percent(Channel::CH0, 100).unwrap();
+        assert_eq!(pwm.get_duty(Channel::CH0), pwm.get_period(Channel::CH0));
+        assert_eq!(pwm.set_duty_percent(Channel::CH0, 101), Err(Error::DutyOutOfRange));
+        assert_eq!(pwm.set_duty_fraction(Channel::CH0, 1, 0), Err(Error::DutyOutOfRange));
+        assert_eq!(pwm.set_duty_fraction(Channel::CH0, 4, 3), Err(Error::DutyOutOfRange));
+        assert_eq!(pwm.get_duty(Channel::CH0), 100_000);
+    }
+
+    #[test]
+    fn config_read_back() {
+        let _session = mock::session();
+        let mut pwm = PWM::new();
+        pwm.set_period(Channel::CH0, 400).unwrap();
+        pwm.set_duty(Channel::CH0, 100).unwrap();
+        let config = pwm.get_config(Channel::CH0);
+        assert_eq!(config, PwmConfig::new().period(400).duty(100));
+        assert_eq!(config.get_period(), Some(400));
+        assert_eq!(config.get_duty(), Duty::Cycles(100));
+        pwm.enable(Channel::CH0);
+        pwm.set_active_low(Channel::CH0);
+        pwm.set_align_center(Channel::CH0);
+        let config = pwm.get_config(Channel::CH0);
+        assert!(config.is_enabled());
+        assert_eq!(config.get_polarity(), Polarity::ActiveLow);
+        assert_eq!(config.get_alignment(), Alignment::Center);
+    }
+
+    #[test]
+    fn config_read_back_matches_how_it_was_built() {
+        let _session = mock::session();
+        let mut pwm = PWM::new();
+        let config = PwmConfig::new().frequency(1_000).duty_percent(25).enabled(true);
+        pwm.configure(Channel::CH0, &config).unwrap();
+        assert_eq!(pwm.get_config(Channel::CH0), config);
+        assert_eq!(config, PwmConfig::new().enabled(true).period(100_000).duty(25_000));
+        assert_ne!(config, config.enabled(false));
+        assert_ne!(config, config.duty_percent(26));
+        // Invalid configs are only equal to configs built the same way
+        let invalid = PwmConfig::new().frequency(0);
+        assert_eq!(invalid, PwmConfig::new().frequency(0));
+        assert_ne!(invalid, PwmConfig::new().frequency(PWM_MAX_FREQ + 1));
+        assert_eq!(invalid.get_period(), None);
+    }
+
+    #[test]
+    fn set_duty_cycle_scales_to_period() {
+        use embedded_hal::pwm::SetDutyCycle;
+        let session = mock::session();
+        let mut ch = PWM::new().split().ch0;
+        ch.set_period(1_000).unwrap();
+        assert_eq!(SetDutyCycle::max_duty_cycle(&ch), 1_000);
+        ch.set_duty_cycle(250).unwrap();
+        assert_eq!(session.read(PWM_DUTY), 250 + AFTX06_DUTY_OFFSET);
+        ch.set_duty_cycle_percent(40).unwrap();
+        assert_eq!(session.read(PWM_DUTY), 400 + AFTX06_DUTY_OFFSET);
+        ch.set_duty_cycle_fully_on().unwrap();
+        assert_eq!(session.read(PWM_DUTY), 1_000 + AFTX06_DUTY_OFFSET);
+        assert_eq!(ch.set_duty_cycle(1_001), Err(Error::DutyOutOfRange));
+        // Long periods are addressed through a u16 fraction
+        ch.set_frequency(1_000).unwrap();
+        assert_eq!(SetDutyCycle::max_duty_cycle(&ch), u16::MAX);
+        ch.set_duty_cycle_fraction(1, 2).unwrap();
+        assert_eq!(ch.get_duty(), 49_999);
+    }
+
+    #[test]
+    fn legacy_pwm_traits() {
+        use embedded_hal_02::{Pwm, PwmPin};
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        Pwm::set_period(&mut pwm, 200u32);
+        assert_eq!(Pwm::get_period(&pwm), 200);
+        assert_eq!(Pwm::get_max_duty(&pwm), 200);
+        Pwm::set_duty(&mut pwm, Channel::CH0, 50);
+        Pwm::enable(&mut pwm, Channel::CH0);
+        assert_eq!(Pwm::get_duty(&pwm, Channel::CH0), 50);
+        assert_eq!(session.read(PWM_CONTROL), PWM_CONTROL_ENABLE);
+        let mut ch = pwm.split().ch0;
+        PwmPin::set_duty(&mut ch, 500);
+        assert_eq!(PwmPin::get_duty(&ch), 200);
+        PwmPin::disable(&mut ch);
+        assert_eq!(session.read(PWM_CONTROL), 0);
+    }
+
+    #[test]
+    fn legacy_set_period_clamps_period_and_duty() {
+        use embedded_hal_02::Pwm;
+        let _session = mock::session();
+        let mut pwm = PWM::new();
+        Pwm::set_period(&mut pwm, 200u32);
+        Pwm::set_duty(&mut pwm, Channel::CH0, 150);
+        Pwm::set_period(&mut pwm, 100u32);
+        assert_eq!(Pwm::get_period(&pwm), 100);
+        assert_eq!(Pwm::get_duty(&pwm, Channel::CH0), 100);
+        Pwm::set_period(&mut pwm, 0u32);
+        assert_eq!(Pwm::get_period(&pwm), PWM_MIN_PERIOD);
+        assert_eq!(Pwm::get_duty(&pwm, Channel::CH0), PWM_MIN_PERIOD);
+        assert!(Pwm::get_duty(&pwm, Channel::CH0) <= Pwm::get_max_duty(&pwm));
+    }
+
+    #[test]
+    fn config_builder_validates() {
+        let config = PwmConfig::new().frequency(1_000).duty_percent(25);
+        assert_eq!(config.get_period(), Some(100_000));
+        assert_eq!(config.get_duty(), Duty::Percent(25));
+        assert_eq!(config.resolve(), Ok((100_000, 25_000)));
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(PwmConfig::new().validate(), Err(Error::InvalidPeriod));
+        assert_eq!(PwmConfig::new().frequency(PWM_MAX_FREQ + 1).validate(), Err(Error::FrequencyOutOfRange));
+        assert_eq!(PwmConfig::new().period(10).duty(11).validate(), Err(Error::DutyOutOfRange));
+        assert_eq!(PwmConfig::new().period(100).duty_percent(101).validate(), Err(Error::DutyOutOfRange));
+    }
+
+    #[test]
+    fn config_resolves_in_any_order() {
+        let config = PwmConfig::new().duty_percent(25).frequency(1_000);
+        assert_eq!(config.resolve(), Ok((100_000, 25_000)));
+        assert_eq!(PwmConfig::new().duty_percent(50).period(400).resolve(), Ok((400, 200)));
+        // The last of duty() and duty_percent() wins, as does the last of period() and frequency()
+        assert_eq!(PwmConfig::new().period(400).duty_percent(50).duty(10).resolve(), Ok((400, 10)));
+        assert_eq!(PwmConfig::new().duty(10).duty_percent(50).period(400).resolve(), Ok((400, 200)));
+        assert_eq!(PwmConfig::new().frequency(0).period(400).resolve(), Ok((400, 0)));
+        // An invalid frequency is reported even when an earlier period was valid
+        assert_eq!(PwmConfig::new().period(400).frequency(0).validate(), Err(Error::FrequencyOutOfRange));
+        assert_eq!(PwmConfig::new().period(400).frequency(PWM_MAX_FREQ + 1).validate(), Err(Error::FrequencyOutOfRange));
+    }
+
+    #[test]
+    fn configure_applies_duty_percent() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        pwm.configure(Channel::CH0, &PwmConfig::new().duty_percent(30).period(1_000)).unwrap();
+        assert_eq!(session.read(PWM_PERIOD), 1_000);
+        assert_eq!(session.read(PWM_DUTY), 300 + AFTX06_DUTY_OFFSET);
+    }
+
+    #[test]
+    fn configure_applies_everything() {
+        let session = mock::session();
+        let mut pwm = PWM::new();
+        let config = PwmConfig::new()
+            .period(400)
+            .duty(100)
+            .polarity(Polarity::ActiveLow)
+            .alignment(Alignment::Center)
+            .enabled(true);
+        pwm.configure(Channel::CH0, &config).unwrap();
+        assert_eq!(session.read(PWM_CONTROL), 0b111);
+        assert_eq!(pwm.get_config(Channel::CH0), config);
+    }
+
+    #[test]
+    fn configure_rejects_without_writing() {
+        let session = mock::session();
+        session.install(Recorder::default());
+        let mut pwm = PWM::new();
+        let config = PwmConfig::new().period(100).duty(101).enabled(true);
+        assert_eq!(pwm.configure(Channel::CH0, &config), Err(Error::DutyOutOfRange));
+        assert!(session.with(|recorder: &mut Recorder| recorder.writes.is_empty()));
+    }
+
+    #[test]
+    fn configure_disables_writes_then_enables() {
+        let session = mock::session();
+        session.install(Recorder::default());
+        let mut pwm = PWM::new();
+        pwm.configure(Channel::CH0, &PwmConfig::new().period(100).duty(50).enabled(true)).unwrap();
+        session.with(|recorder: &mut Recorder| recorder.writes.clear());
+        let config = PwmConfig::new().period(200).duty(20).alignment(Alignment::Center).enabled(true);
+        pwm.configure(Channel::CH0, &config).unwrap();
+        let writes: Vec<(u32, u32)> = session.with(|recorder: &mut Recorder| recorder.writes.clone());
+        assert_eq!(writes, vec![
+            (PWM_CONTROL, 0),
+            (PWM_PERIOD, 200),
+            (PWM_DUTY, 20 + AFTX06_DUTY_OFFSET),
+            (PWM_CONTROL, PWM_CONTROL_ENABLE | PWM_CONTROL_ALIGN_CENTER),
+        ]);
+    }
+}
@@ -1,11 +1,11 @@
-use crate::common;
-use volatile_register::{RW};
-
-// Timer Construction Check
-pub static mut TIM_CONSTRUCTED: bool =   false;
+use crate::common::{self, Error};
+use crate::peripherals::{self, AlreadyTaken};
+use crate::register::{self, Block, RW};
+use crate::time::{Duration, Hertz};
 
 // Timer Constants
 pub const TIM: u32 =                       0x80020000;
+#[allow(clippy::identity_op)]
 pub const TIM_IOS: u32 =                   TIM + 0x00;
 pub const TIM_TCF: u32 =                   TIM + 0x04;
 pub const TIM_TCNT: u32 =                  TIM + 0x08;
@@ -16,6 +16,7 @@ pub const TIM_TIE: u32 =                   TIM + 0x18;
 pub const TIM_TSCR2: u32 =                 TIM + 0x1C;
 pub const TIM_FLG1: u32 =                  TIM + 0x20;
 pub const TIM_FLG2: u32 =                  TIM + 0x24;
+pub const TIM_RLV: u32 =                   TIM + 0x48;
 pub const TIM_TCF_MASK: u32 =              0xFF;
 pub const TIM_TSCR_ENABLE: u32 =           1 << 7;
 pub const TIM_TSCR_DISABLE: u32 =          !(1 << 7);
@@ -46,6 +47,7 @@ pub const TIM_TSCR2_PRE_DIV128: u32 =      7;
 pub const TIM_FLG1_MASK: u32 =             0xFF;
 pub const TIM_FLG2_CLEAR: u32 =            1 << 7;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Channel {
     CH0,
     CH1,
@@ -57,6 +59,38 @@ pub enum Channel {
     CH7,
 }
 
+impl Channel {
+    pub const ALL: [Channel; 8] = [
+        Channel::CH0, Channel::CH1, Channel::CH2, Channel::CH3,
+        Channel::CH4, Channel::CH5, Channel::CH6, Channel::CH7,
+    ];
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputAction {
+    Disconnect = TIM_TCR_OUTPUT_DISCONNECT,
+    Toggle =     TIM_TCR_OUTPUT_TOGGLE,
+    Clear =      TIM_TCR_OUTPUT_CLEAR,
+    Set =        TIM_TCR_OUTPUT_SET,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaptureEdge {
+    Disabled = TIM_TCR_EDGE_DISABLE,
+    Falling =  TIM_TCR_EDGE_FALLING,
+    Rising =   TIM_TCR_EDGE_RISING,
+    Either =   TIM_TCR_EDGE_EITHER,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptMode {
+    Disabled,
+    Enabled,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Pre {
     DIV1 =   0,
     DIV2 =   1,
@@ -68,8 +102,90 @@ pub enum Pre {
     DIV128 = 7,
 }
 
+impl Pre {
+    pub const ALL: [Pre; 8] = [
+        Pre::DIV1, Pre::DIV2, Pre::DIV4, Pre::DIV8,
+        Pre::DIV16, Pre::DIV32, Pre::DIV64, Pre::DIV128,
+    ];
+
+    pub fn divider(&self) -> u32 {
+        1 << (*self as u32)
+    }
+}
+
+// Desired counter period, given either as a rate or as a length of time
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Period {
+    Frequency(Hertz),
+    Duration(Duration),
+}
+
+impl From<Hertz> for Period {
+    fn from(frequency: Hertz) -> Period {
+        Period::Frequency(frequency)
+    }
+}
+
+impl From<Duration> for Period {
+    fn from(duration: Duration) -> Period {
+        Period::Duration(duration)
+    }
+}
+
+// Prescaler and TC7 value that best realise a requested period with TCRE set.
+// The counter runs 0..=compare, so one period is compare + 1 prescaled counts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PeriodConfig {
+    pub prescaler: Pre,
+    pub compare: u32,
+    pub period: Duration,
+    pub error_ppm: i32,
+}
+
+impl PeriodConfig {
+    pub fn counts(&self) -> u64 {
+        self.compare as u64 + 1
+    }
+
+    pub fn frequency(&self) -> Hertz {
+        let ticks: u64 = self.period.as_ticks();
+        Hertz(((common::CHIP_FREQ as u64 + ticks / 2) / ticks) as u32)
+    }
+}
+
 pub struct TIM {
-    p: &'static mut TIMRegisterBlock
+    p: Block<TIMRegisterBlock>
+}
+
+// Counter half of a split TIM: enable, prescaler, counter reset, reload and
+// the overflow flag. No channel writes these registers.
+pub struct TIMCounter {
+    p: Block<TIMRegisterBlock>
+}
+
+// One channel of a split TIM. Channels share IOS, TCR, TIE, TCF and TOV, so
+// every read-modify-write of those runs inside a critical section and the
+// handle can be used through &self from any context.
+pub struct TIMChannel {
+    p: Block<TIMRegisterBlock>,
+    channel: Channel
+}
+
+// Writes to the channel's own registers are single stores and the shared ones
+// are changed inside critical sections, so a channel may be used from several
+// contexts at once, for example by a driver that an interrupt handler polls
+unsafe impl Sync for TIMChannel {}
+
+pub struct Parts {
+    pub counter: TIMCounter,
+    pub ch0: TIMChannel,
+    pub ch1: TIMChannel,
+    pub ch2: TIMChannel,
+    pub ch3: TIMChannel,
+    pub ch4: TIMChannel,
+    pub ch5: TIMChannel,
+    pub ch6: TIMChannel,
+    pub ch7: TIMChannel,
 }
 
 #[repr(C)]
@@ -97,16 +213,53 @@ struct TIMRegisterBlock {
 
 impl TIM {
     pub fn new() -> TIM {
+        match TIM::try_new() {
+            Ok(tim) => tim,
+            Err(_) => panic!("You may construct only one instance of TIM."),
+        }
+    }
+
+    pub fn try_new() -> Result<TIM, AlreadyTaken> {
+        if peripherals::claim(peripherals::TIM_TAKEN) {
+            Ok(unsafe { TIM::steal() })
+        }
+        else {
+            Err(AlreadyTaken)
+        }
+    }
+
+    // Returns the peripheral to its reset state so it can be constructed again
+    pub fn free(self) {
         unsafe {
-            if TIM_CONSTRUCTED == false {
-                TIM_CONSTRUCTED = true;
-                TIM {
-                    p: &mut *(0x8002_0000 as *mut TIMRegisterBlock)
-                }
-            }
-            else {
-                panic!("You may construct only one instance of TIM.")
-            }
+            self.p.tscr.write(0);
+            self.p.tie.write(0);
+            self.p.ios.write(0);
+            self.p.tcf.write(0);
+            self.p.tov.write(0);
+            self.p.tcr.write(0);
+            self.p.tscr2.write(0);
+            self.p.tflg1.write(TIM_FLG1_MASK);
+            self.p.tflg2.write(TIM_FLG2_CLEAR);
+            self.p.tcnt.write(0);
+            self.p.tc0.write(0);
+            self.p.tc1.write(0);
+            self.p.tc2.write(0);
+            self.p.tc3.write(0);
+            self.p.tc4.write(0);
+            self.p.tc5.write(0);
+            self.p.tc6.write(0);
+            self.p.tc7.write(0);
+            self.p.rlv.write(0);
+        }
+        peripherals::release(peripherals::TIM_TAKEN);
+    }
+
+    /// # Safety
+    /// Skips the ownership check. The caller must not race the owning instance,
+    /// e.g. by only using the stolen copy inside an interrupt handler.
+    pub unsafe fn steal() -> TIM {
+        TIM {
+            p: register::block(0x8002_0000)
         }
     }
 
@@ -126,40 +279,36 @@ impl TIM {
         }
     }
 
-    pub fn set_output_action(&mut self, channel: Channel, output_action: u32) {
+    pub fn set_output_action(&mut self, channel: Channel, output_action: OutputAction) {
+        let n: u32 = channel as u32;
         unsafe {
             let mut curr: u32 = self.p.tcr.read();
-            match channel {
-                Channel::CH0 => curr = (curr & !(common::tim_tcr_output_mask(0))) | (common::tim_tcr_output_mask(0) & (output_action << 0)),
-                Channel::CH1 => curr = (curr & !(common::tim_tcr_output_mask(1))) | (common::tim_tcr_output_mask(1) & (output_action << 1)),
-                Channel::CH2 => curr = (curr & !(common::tim_tcr_output_mask(2))) | (common::tim_tcr_output_mask(2) & (output_action << 2)),
-                Channel::CH3 => curr = (curr & !(common::tim_tcr_output_mask(3))) | (common::tim_tcr_output_mask(3) & (output_action << 3)),
-                Channel::CH4 => curr = (curr & !(common::tim_tcr_output_mask(4))) | (common::tim_tcr_output_mask(4) & (output_action << 4)),
-                Channel::CH5 => curr = (curr & !(common::tim_tcr_output_mask(5))) | (common::tim_tcr_output_mask(5) & (output_action << 5)),
-                Channel::CH6 => curr = (curr & !(common::tim_tcr_output_mask(6))) | (common::tim_tcr_output_mask(6) & (output_action << 6)),
-                Channel::CH7 => curr = (curr & !(common::tim_tcr_output_mask(7))) | (common::tim_tcr_output_mask(7) & (output_action << 7)),
-            }
+            curr = (curr & !common::tim_tcr_output_mask(n)) | ((output_action as u32) << n);
             self.p.tcr.write(curr);
         }
     }
 
-    pub fn set_input_capture_edge(&mut self, channel: Channel, capture_edge: u32) {
+    pub fn set_input_capture_edge(&mut self, channel: Channel, capture_edge: CaptureEdge) {
+        let n: u32 = channel as u32;
         unsafe {
             let mut curr: u32 = self.p.tcr.read();
-            match channel {
-                Channel::CH0 => curr = (curr & !(common::tim_tcr_edge_mask(0))) | (common::tim_tcr_edge_mask(0) & (capture_edge << 0)),
-                Channel::CH1 => curr = (curr & !(common::tim_tcr_edge_mask(1))) | (common::tim_tcr_edge_mask(1) & (capture_edge << 1)),
-                Channel::CH2 => curr = (curr & !(common::tim_tcr_edge_mask(2))) | (common::tim_tcr_edge_mask(2) & (capture_edge << 2)),
-                Channel::CH3 => curr = (curr & !(common::tim_tcr_edge_mask(3))) | (common::tim_tcr_edge_mask(3) & (capture_edge << 3)),
-                Channel::CH4 => curr = (curr & !(common::tim_tcr_edge_mask(4))) | (common::tim_tcr_edge_mask(4) & (capture_edge << 4)),
-                Channel::CH5 => curr = (curr & !(common::tim_tcr_edge_mask(5))) | (common::tim_tcr_edge_mask(5) & (capture_edge << 5)),
-                Channel::CH6 => curr = (curr & !(common::tim_tcr_edge_mask(6))) | (common::tim_tcr_edge_mask(6) & (capture_edge << 6)),
-                Channel::CH7 => curr = (curr & !(common::tim_tcr_edge_mask(7))) | (common::tim_tcr_edge_mask(7) & (capture_edge << 7)),
-            }
+            curr = (curr & !common::tim_tcr_edge_mask(n)) | ((capture_edge as u32) << n);
             self.p.tcr.write(curr);
         }
     }
 
+    pub fn set_interrupt_mode(&mut self, channel: Channel, interrupt_mode: InterruptMode) {
+        let mask: u32 = common::tim_tie_mask(channel as u32);
+        unsafe {
+            let mut curr: u32 = self.p.tie.read();
+            match interrupt_mode {
+                InterruptMode::Disabled => curr &= !mask,
+                InterruptMode::Enabled =>  curr |= mask,
+            }
+            self.p.tie.write(curr);
+        }
+    }
+
     pub fn set_prescaler(&mut self, pre_div: Pre) {
         unsafe {
             let mut curr: u32 = self.p.tscr2.read();
@@ -177,254 +326,773 @@ impl TIM {
         }
     }
 
-    pub fn set_output_compare(&mut self, channel: Channel, output_action: u32, interrupt_enable: u32, value: u32) {
+    // Makes channel an output compare channel matching TCNT against value
+    pub fn set_output_compare(&mut self, channel: Channel, output_action: OutputAction, interrupt_mode: InterruptMode, value: u32) {
         unsafe {
-            let mut curr: u32 = self.p.tcr.read();
-            match channel {
-                Channel::CH0 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(0))) | (common::tim_tcr_output_mask(0) & (output_action << 0));
-                    self.p.tcr.write(curr);
-                    self.p.tc0.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(0))) | (common::tim_tie_mask(0) & (interrupt_enable << 0));
-                }
-                Channel::CH1 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(1))) | (common::tim_tcr_output_mask(1) & (output_action << 1));
-                    self.p.tcr.write(curr);
-                    self.p.tc1.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(1))) | (common::tim_tie_mask(1) & (interrupt_enable << 1));
-                }
-                Channel::CH2 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(2))) | (common::tim_tcr_output_mask(2) & (output_action << 2));
-                    self.p.tcr.write(curr);
-                    self.p.tc2.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(2))) | (common::tim_tie_mask(2) & (interrupt_enable << 2));
-                }
-                Channel::CH3 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(3))) | (common::tim_tcr_output_mask(3) & (output_action << 3));
-                    self.p.tcr.write(curr);
-                    self.p.tc3.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(3))) | (common::tim_tie_mask(3) & (interrupt_enable << 3));
-                }
-                Channel::CH4 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(4))) | (common::tim_tcr_output_mask(4) & (output_action << 4));
-                    self.p.tcr.write(curr);
-                    self.p.tc4.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(4))) | (common::tim_tie_mask(4) & (interrupt_enable << 4));
-                }
-                Channel::CH5 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(5))) | (common::tim_tcr_output_mask(5) & (output_action << 5));
-                    self.p.tcr.write(curr);
-                    self.p.tc5.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(5))) | (common::tim_tie_mask(5) & (interrupt_enable << 5));
-                }
-                Channel::CH6 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(6))) | (common::tim_tcr_output_mask(6) & (output_action << 6));
-                    self.p.tcr.write(curr);
-                    self.p.tc6.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(6))) | (common::tim_tie_mask(6) & (interrupt_enable << 6));
-                }
-                Channel::CH7 => {
-                    curr = (curr & !(common::tim_tcr_output_mask(7))) | (common::tim_tcr_output_mask(7) & (output_action << 7));
-                    self.p.tcr.write(curr);
-                    self.p.tc7.write(value);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(7))) | (common::tim_tie_mask(7) & (interrupt_enable << 7));
-                }
-            }
-            self.p.tie.write(curr);
+            let mut curr: u32 = self.p.ios.read();
+            curr |= common::tim_ios_output(channel as u32);
+            self.p.ios.write(curr);
+            self.tc(channel).write(value);
         }
+        self.set_output_action(channel, output_action);
+        self.set_interrupt_mode(channel, interrupt_mode);
     }
 
-    pub fn set_input_capture(&mut self, channel: Channel, capture_edge: u32, interrupt_enable: u32) {
+    // Makes channel an input capture channel latching TCNT on capture_edge
+    pub fn set_input_capture(&mut self, channel: Channel, capture_edge: CaptureEdge, interrupt_mode: InterruptMode) {
         unsafe {
-            let mut curr = self.p.tcr.read();
-            match channel {
-                Channel::CH0 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(0))) | (common::tim_tcr_edge_mask(0) & (capture_edge << 0));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(0))) | (common::tim_tie_mask(0) & (interrupt_enable << 0));
-                }
-                Channel::CH1 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(1))) | (common::tim_tcr_edge_mask(1) & (capture_edge << 1));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(1))) | (common::tim_tie_mask(1) & (interrupt_enable << 1));
-                }
-                Channel::CH2 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(2))) | (common::tim_tcr_edge_mask(2) & (capture_edge << 2));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(2))) | (common::tim_tie_mask(2) & (interrupt_enable << 2));
-                }
-                Channel::CH3 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(3))) | (common::tim_tcr_edge_mask(3) & (capture_edge << 3));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(3))) | (common::tim_tie_mask(3) & (interrupt_enable << 3));
-                }
-                Channel::CH4 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(4))) | (common::tim_tcr_edge_mask(4) & (capture_edge << 4));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(4))) | (common::tim_tie_mask(4) & (interrupt_enable << 4));
-                }
-                Channel::CH5 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(5))) | (common::tim_tcr_edge_mask(5) & (capture_edge << 5));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(5))) | (common::tim_tie_mask(5) & (interrupt_enable << 5));
-                }
-                Channel::CH6 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(6))) | (common::tim_tcr_edge_mask(6) & (capture_edge << 6));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(6))) | (common::tim_tie_mask(6) & (interrupt_enable << 6));
-                }
-                Channel::CH7 => {
-                    curr = (curr & !(common::tim_tcr_edge_mask(7))) | (common::tim_tcr_edge_mask(7) & (capture_edge << 7));
-                    self.p.tcr.write(curr);
-                    curr = self.p.tie.read();
-                    curr = (curr & !(common::tim_tie_mask(7))) | (common::tim_tie_mask(7) & (interrupt_enable << 7));
-                }
-            }
-            self.p.tie.write(curr);
+            let mut curr: u32 = self.p.ios.read();
+            curr &= common::tim_ios_input(channel as u32);
+            self.p.ios.write(curr);
         }
+        self.set_input_capture_edge(channel, capture_edge);
+        self.set_interrupt_mode(channel, interrupt_mode);
     }
 
-    pub fn read_input_capture(&self, channel: Channel) -> u32 {
-        match channel {
-            Channel::CH0 => self.p.tc0.read(),
-            Channel::CH1 => self.p.tc1.read(),
-            Channel::CH2 => self.p.tc2.read(),
-            Channel::CH3 => self.p.tc3.read(),
-            Channel::CH4 => self.p.tc4.read(),
-            Channel::CH5 => self.p.tc5.read(),
-            Channel::CH6 => self.p.tc6.read(),
-            Channel::CH7 => self.p.tc7.read(),
+    pub fn set_compare_value(&mut self, channel: Channel, value: u32) {
+        unsafe {
+            self.tc(channel).write(value);
         }
     }
 
+    pub fn read_input_capture(&self, channel: Channel) -> u32 {
+        self.tc(channel).read()
+    }
+
+    pub fn interrupt_flag(&self, channel: Channel) -> bool {
+        self.p.tflg1.read() & common::timn(channel as u32) != 0
+    }
+
+    pub fn interrupt_flags(&self) -> u32 {
+        self.p.tflg1.read() & TIM_FLG1_MASK
+    }
+
+    // TFLG1 is write-1-to-clear, so only the channel's own bit is written
     pub fn clear_interrupt(&mut self, channel: Channel) {
         unsafe {
-            let mut curr = self.p.tflg1.read();
-            match channel {
-                Channel::CH0 => curr |= TIM_FLG1 & 0,
-                Channel::CH1 => curr |= TIM_FLG1 & 1,
-                Channel::CH2 => curr |= TIM_FLG1 & 2,
-                Channel::CH3 => curr |= TIM_FLG1 & 3,
-                Channel::CH4 => curr |= TIM_FLG1 & 4,
-                Channel::CH5 => curr |= TIM_FLG1 & 5,
-                Channel::CH6 => curr |= TIM_FLG1 & 6,
-                Channel::CH7 => curr |= TIM_FLG1 & 7,
-            }
-            self.p.tflg1.write(curr);
+            self.p.tflg1.write(common::timn(channel as u32));
         }
     }
-    
-    pub fn clear_interrupts(&mut self, channels: u32) {
-        if channels > (common::U8_MAX as u32)
-        {
-            panic!("Channels must be of type u8.")
+
+    pub fn clear_interrupts(&mut self, channels: u32) -> Result<(), Error> {
+        if channels & !TIM_FLG1_MASK != 0 {
+            return Err(Error::InvalidChannelMask);
         }
         unsafe {
-            let mut curr = self.p.tflg1.read();
-            curr |= TIM_FLG1 & channels;
-            self.p.tflg1.write(curr);
+            self.p.tflg1.write(channels);
         }
+        Ok(())
     }
 
     pub fn enable_cf(&mut self, channel: Channel) {
         unsafe {
-            let mut curr = self.p.tcf.read();
-            match channel {
-                Channel::CH0 => curr |= TIM_TCF_MASK & 0,
-                Channel::CH1 => curr |= TIM_TCF_MASK & 1,
-                Channel::CH2 => curr |= TIM_TCF_MASK & 2,
-                Channel::CH3 => curr |= TIM_TCF_MASK & 3,
-                Channel::CH4 => curr |= TIM_TCF_MASK & 4,
-                Channel::CH5 => curr |= TIM_TCF_MASK & 5,
-                Channel::CH6 => curr |= TIM_TCF_MASK & 6,
-                Channel::CH7 => curr |= TIM_TCF_MASK & 7,
-            }
+            let mut curr: u32 = self.p.tcf.read();
+            curr |= common::timn(channel as u32);
             self.p.tcf.write(curr);
         }
     }
 
-    pub fn enable_cfs(&mut self, channels: u32) {
-        if channels > (common::U8_MAX as u32)
-        {
-            panic!("Channels must be of type u8.")
+    pub fn enable_cfs(&mut self, channels: u32) -> Result<(), Error> {
+        if channels & !TIM_TCF_MASK != 0 {
+            return Err(Error::InvalidChannelMask);
         }
         unsafe {
-            let mut curr = self.p.tcf.read();
-            curr |= TIM_TCF_MASK & channels;
+            let mut curr: u32 = self.p.tcf.read();
+            curr |= channels;
             self.p.tcf.write(curr);
         }
+        Ok(())
     }
 
     pub fn enable_tov(&mut self, channel: Channel) {
         unsafe {
-            let mut curr = self.p.tov.read();
-            match channel {
-                Channel::CH0 => curr |= TIM_TOV_MASK & 0,
-                Channel::CH1 => curr |= TIM_TOV_MASK & 1,
-                Channel::CH2 => curr |= TIM_TOV_MASK & 2,
-                Channel::CH3 => curr |= TIM_TOV_MASK & 3,
-                Channel::CH4 => curr |= TIM_TOV_MASK & 4,
-                Channel::CH5 => curr |= TIM_TOV_MASK & 5,
-                Channel::CH6 => curr |= TIM_TOV_MASK & 6,
-                Channel::CH7 => curr |= TIM_TOV_MASK & 7,
-            }
+            let mut curr: u32 = self.p.tov.read();
+            curr |= common::timn(channel as u32);
             self.p.tov.write(curr);
         }
     }
 
-    pub fn enable_tovs(&mut self, channels: u32) {
+    pub fn enable_tovs(&mut self, channels: u32) -> Result<(), Error> {
+        if channels & !TIM_TOV_MASK != 0 {
+            return Err(Error::InvalidChannelMask);
+        }
         unsafe {
-            let mut curr = self.p.tov.read();
-            curr |= TIM_TOV_MASK & channels;
+            let mut curr: u32 = self.p.tov.read();
+            curr |= channels;
             self.p.tov.write(curr);
         }
+        Ok(())
     }
 
     pub fn disable_tov(&mut self, channel: Channel) {
         unsafe {
-            let mut curr = self.p.tov.read();
-            match channel {
-                Channel::CH0 => curr &= !(TIM_TOV_MASK) | !(0),
-                Channel::CH1 => curr &= !(TIM_TOV_MASK) | !(1),
-                Channel::CH2 => curr &= !(TIM_TOV_MASK) | !(2),
-                Channel::CH3 => curr &= !(TIM_TOV_MASK) | !(3),
-                Channel::CH4 => curr &= !(TIM_TOV_MASK) | !(4),
-                Channel::CH5 => curr &= !(TIM_TOV_MASK) | !(5),
-                Channel::CH6 => curr &= !(TIM_TOV_MASK) | !(6),
-                Channel::CH7 => curr &= !(TIM_TOV_MASK) | !(7),
-            }
+            let mut curr: u32 = self.p.tov.read();
+            curr &= !common::timn(channel as u32);
             self.p.tov.write(curr);
         }
     }
 
-    pub fn disable_tovs(&mut self, channels: u32) {
-        if channels > (common::U8_MAX as u32)
-        {
-            panic!("Channels must be of type u8.")
+    pub fn disable_tovs(&mut self, channels: u32) -> Result<(), Error> {
+        if channels & !TIM_TOV_MASK != 0 {
+            return Err(Error::InvalidChannelMask);
         }
         unsafe {
-            let mut curr = self.p.tov.read();
-            curr &= !(TIM_TOV_MASK) | !channels;
+            let mut curr: u32 = self.p.tov.read();
+            curr &= !channels;
             self.p.tov.write(curr);
         }
+        Ok(())
     }
 
     pub fn read_count(&self) -> u32 {
         self.p.tcnt.read()
     }
-}
\ No newline at end of file
+
+    pub fn enable_overflow_interrupt(&mut self) {
+        unsafe {
+            let mut curr: u32 = self.p.tscr2.read();
+            curr |= TIM_TSCR2_TOI_ENABLE;
+            self.p.tscr2.write(curr);
+        }
+    }
+
+    pub fn disable_overflow_interrupt(&mut self) {
+        unsafe {
+            let mut curr: u32 = self.p.tscr2.read();
+            curr &= TIM_TSCR2_TOI_DISABLE;
+            self.p.tscr2.write(curr);
+        }
+    }
+
+    pub fn overflow_flag(&self) -> bool {
+        self.p.tflg2.read() & TIM_FLG2_CLEAR != 0
+    }
+
+    // TFLG2 is write-1-to-clear, so only the overflow bit is written
+    pub fn clear_overflow_flag(&mut self) {
+        unsafe {
+            self.p.tflg2.write(TIM_FLG2_CLEAR);
+        }
+    }
+
+    // Resets TCNT after each successful channel 7 output compare
+    pub fn enable_counter_reset(&mut self) {
+        unsafe {
+            let mut curr: u32 = self.p.tscr2.read();
+            curr |= TIM_TSCR2_TCRE_ENABLE;
+            self.p.tscr2.write(curr);
+        }
+    }
+
+    pub fn disable_counter_reset(&mut self) {
+        unsafe {
+            let mut curr: u32 = self.p.tscr2.read();
+            curr &= TIM_TSCR2_TCRE_DISABLE;
+            self.p.tscr2.write(curr);
+        }
+    }
+
+    // Value loaded into TCNT when it overflows
+    pub fn set_reload_value(&mut self, value: u32) {
+        unsafe {
+            self.p.rlv.write(value);
+        }
+    }
+
+    pub fn get_reload_value(&self) -> u32 {
+        self.p.rlv.read()
+    }
+
+    pub fn get_prescaler(&self) -> Pre {
+        match self.p.tscr2.read() & TIM_TSCR2_PRE_MASK {
+            TIM_TSCR2_PRE_DIV1 =>  Pre::DIV1,
+            TIM_TSCR2_PRE_DIV2 =>  Pre::DIV2,
+            TIM_TSCR2_PRE_DIV4 =>  Pre::DIV4,
+            TIM_TSCR2_PRE_DIV8 =>  Pre::DIV8,
+            TIM_TSCR2_PRE_DIV16 => Pre::DIV16,
+            TIM_TSCR2_PRE_DIV32 => Pre::DIV32,
+            TIM_TSCR2_PRE_DIV64 => Pre::DIV64,
+            _ =>                   Pre::DIV128,
+        }
+    }
+
+    pub fn count_frequency(&self) -> u32 {
+        common::CHIP_FREQ >> (self.get_prescaler() as u32)
+    }
+
+    // Picks the prescaler and compare value whose period is closest to the target,
+    // preferring the finer prescaler when two are equally close
+    pub fn solve_period(target: impl Into<Period>) -> Result<PeriodConfig, Error> {
+        // Target period in CPU cycles is num / den
+        let (num, den, out_of_range): (u64, u64, Error) = match target.into() {
+            Period::Frequency(Hertz(frequency)) => {
+                if frequency == 0 || common::CHIP_FREQ < frequency {
+                    return Err(Error::FrequencyOutOfRange);
+                }
+                (common::CHIP_FREQ as u64, frequency as u64, Error::FrequencyOutOfRange)
+            }
+            Period::Duration(duration) => {
+                if duration.is_zero() {
+                    return Err(Error::InvalidPeriod);
+                }
+                (duration.as_ticks(), 1, Error::InvalidPeriod)
+            }
+        };
+        let mut best: Option<(Pre, u64, u128)> = None;
+        for &pre in Pre::ALL.iter() {
+            let divisor: u64 = den << (pre as u32);
+            let counts: u64 = num / divisor + (2 * (num % divisor) >= divisor) as u64;
+            if counts == 0 || counts > u32::MAX as u64 + 1 {
+                continue;
+            }
+            let cycles: u64 = counts << (pre as u32);
+            let miss: u128 = ((cycles as u128) * (den as u128)).abs_diff(num as u128);
+            if best.map_or(true, |(_, _, least)| miss < least) {
+                best = Some((pre, counts, miss));
+            }
+        }
+        let (prescaler, counts, _) = best.ok_or(out_of_range)?;
+        let cycles: u64 = counts << (prescaler as u32);
+        let error: i128 = (cycles as i128) * (den as i128) - (num as i128);
+        let error_ppm: i128 = error * 1_000_000 / (num as i128);
+        Ok(PeriodConfig {
+            prescaler,
+            compare: (counts - 1) as u32,
+            period: Duration::from_ticks(cycles),
+            error_ppm: error_ppm.clamp(i32::MIN as i128, i32::MAX as i128) as i32,
+        })
+    }
+
+    // Programs the prescaler, TC7 and TCRE so the counter repeats with the target period
+    pub fn set_period(&mut self, target: impl Into<Period>) -> Result<PeriodConfig, Error> {
+        let config: PeriodConfig = TIM::solve_period(target)?;
+        self.apply_period(&config);
+        Ok(config)
+    }
+
+    pub fn apply_period(&mut self, config: &PeriodConfig) {
+        self.set_prescaler(config.prescaler);
+        self.set_compare_value(Channel::CH7, config.compare);
+        self.enable_counter_reset();
+    }
+
+    // Consumes TIM so each channel can go to its own driver
+    pub fn split(self) -> Parts {
+        let p: Block<TIMRegisterBlock> = self.p;
+        let channel = |channel: Channel| TIMChannel { p, channel };
+        Parts {
+            counter: TIMCounter { p },
+            ch0: channel(Channel::CH0),
+            ch1: channel(Channel::CH1),
+            ch2: channel(Channel::CH2),
+            ch3: channel(Channel::CH3),
+            ch4: channel(Channel::CH4),
+            ch5: channel(Channel::CH5),
+            ch6: channel(Channel::CH6),
+            ch7: channel(Channel::CH7),
+        }
+    }
+
+    fn tc(&self, channel: Channel) -> &RW<u32> {
+        match channel {
+            Channel::CH0 => &self.p.tc0,
+            Channel::CH1 => &self.p.tc1,
+            Channel::CH2 => &self.p.tc2,
+            Channel::CH3 => &self.p.tc3,
+            Channel::CH4 => &self.p.tc4,
+            Channel::CH5 => &self.p.tc5,
+            Channel::CH6 => &self.p.tc6,
+            Channel::CH7 => &self.p.tc7,
+        }
+    }
+}
+
+impl TIMCounter {
+    // The split handles share TIM's register code through a view that is never freed
+    fn tim(&self) -> TIM {
+        TIM { p: self.p }
+    }
+
+    pub fn enable(&mut self) {
+        self.tim().enable();
+    }
+
+    pub fn disable(&mut self) {
+        self.tim().disable();
+    }
+
+    // Enabled with counter reset off and a zero reload value, so TCNT runs
+    // through all 32 bits as the capture and waveform drivers expect
+    pub fn start_free_running(&mut self) {
+        self.disable_counter_reset();
+        self.set_reload_value(0);
+        self.enable();
+    }
+
+    pub fn set_prescaler(&mut self, pre_div: Pre) {
+        self.tim().set_prescaler(pre_div);
+    }
+
+    pub fn get_prescaler(&self) -> Pre {
+        self.tim().get_prescaler()
+    }
+
+    pub fn count_frequency(&self) -> u32 {
+        self.tim().count_frequency()
+    }
+
+    pub fn read_count(&self) -> u32 {
+        self.tim().read_count()
+    }
+
+    pub fn enable_overflow_interrupt(&mut self) {
+        self.tim().enable_overflow_interrupt();
+    }
+
+    pub fn disable_overflow_interrupt(&mut self) {
+        self.tim().disable_overflow_interrupt();
+    }
+
+    pub fn overflow_flag(&self) -> bool {
+        self.tim().overflow_flag()
+    }
+
+    pub fn clear_overflow_flag(&mut self) {
+        self.tim().clear_overflow_flag();
+    }
+
+    // Resets TCNT after each successful channel 7 output compare
+    pub fn enable_counter_reset(&mut self) {
+        self.tim().enable_counter_reset();
+    }
+
+    pub fn disable_counter_reset(&mut self) {
+        self.tim().disable_counter_reset();
+    }
+
+    pub fn set_reload_value(&mut self, value: u32) {
+        self.tim().set_reload_value(value);
+    }
+
+    pub fn get_reload_value(&self) -> u32 {
+        self.tim().get_reload_value()
+    }
+}
+
+impl TIMChannel {
+    fn tim(&self) -> TIM {
+        TIM { p: self.p }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn set_output_action(&self, output_action: OutputAction) {
+        critical_section::with(|_| self.tim().set_output_action(self.channel, output_action));
+    }
+
+    pub fn set_input_capture_edge(&self, capture_edge: CaptureEdge) {
+        critical_section::with(|_| self.tim().set_input_capture_edge(self.channel, capture_edge));
+    }
+
+    pub fn set_interrupt_mode(&self, interrupt_mode: InterruptMode) {
+        critical_section::with(|_| self.tim().set_interrupt_mode(self.channel, interrupt_mode));
+    }
+
+    pub fn set_output_compare(&self, output_action: OutputAction, interrupt_mode: InterruptMode, value: u32) {
+        critical_section::with(|_| self.tim().set_output_compare(self.channel, output_action, interrupt_mode, value));
+    }
+
+    pub fn set_input_capture(&self, capture_edge: CaptureEdge, interrupt_mode: InterruptMode) {
+        critical_section::with(|_| self.tim().set_input_capture(self.channel, capture_edge, interrupt_mode));
+    }
+
+    pub fn set_compare_value(&self, value: u32) {
+        self.tim().set_compare_value(self.channel, value);
+    }
+
+    pub fn read_input_capture(&self) -> u32 {
+        self.tim().read_input_capture(self.channel)
+    }
+
+    pub fn interrupt_flag(&self) -> bool {
+        self.tim().interrupt_flag(self.channel)
+    }
+
+    pub fn clear_interrupt(&self) {
+        self.tim().clear_interrupt(self.channel);
+    }
+
+    // Applies the output action now, as if the compare had matched
+    pub fn force_compare(&self) {
+        critical_section::with(|_| self.tim().enable_cf(self.channel));
+    }
+
+    pub fn enable_tov(&self) {
+        critical_section::with(|_| self.tim().enable_tov(self.channel));
+    }
+
+    pub fn disable_tov(&self) {
+        critical_section::with(|_| self.tim().disable_tov(self.channel));
+    }
+
+    // The counter and prescaler are shared, so channels may read but not change them
+    pub fn read_count(&self) -> u32 {
+        self.tim().read_count()
+    }
+
+    pub fn get_prescaler(&self) -> Pre {
+        self.tim().get_prescaler()
+    }
+
+    pub fn count_frequency(&self) -> u32 {
+        self.tim().count_frequency()
+    }
+}
+
+// Extends a free running TCNT to 64 bits from successive reads of it. Drivers
+// on a split TIM each keep their own, so they must read the count at least
+// once per wrap and the counter must not be reset or reloaded.
+#[derive(Clone, Copy)]
+pub(crate) struct Timeline {
+    now: u64,
+    last: u32,
+}
+
+impl Timeline {
+    // Starts one wrap in so values latched up to a wrap before the first read stay positive
+    pub(crate) fn new(count: u32) -> Timeline {
+        Timeline { now: count as u64 + (1 << 32), last: count }
+    }
+
+    pub(crate) fn update(&mut self, count: u32) -> u64 {
+        self.now += count.wrapping_sub(self.last) as u64;
+        self.last = count;
+        self.now
+    }
+
+    // Places a value latched less than one wrap before the last read
+    pub(crate) fn extend(&self, value: u32) -> u64 {
+        self.now - self.last.wrapping_sub(value) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, sim::Simulator};
+
+    #[test]
+    fn enable_and_disable() {
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.enable();
+        assert_eq!(session.read(TIM_TSCR), TIM_TSCR_ENABLE);
+        tim.disable();
+        assert_eq!(session.read(TIM_TSCR), 0);
+    }
+
+    #[test]
+    fn prescaler() {
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.set_prescaler(Pre::DIV64);
+        assert_eq!(session.read(TIM_TSCR2), TIM_TSCR2_PRE_DIV64);
+        assert_eq!(tim.get_prescaler(), Pre::DIV64);
+        assert_eq!(tim.count_frequency(), common::CHIP_FREQ / 64);
+    }
+
+    #[test]
+    fn count_and_capture() {
+        let session = mock::session();
+        let tim = TIM::new();
+        session.write(TIM_TCNT, 1234);
+        session.write(common::tim_tcn(5), 99);
+        assert_eq!(tim.read_count(), 1234);
+        assert_eq!(tim.read_input_capture(Channel::CH5), 99);
+    }
+
+    #[test]
+    fn typed_channel_configuration() {
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.set_output_compare(Channel::CH3, OutputAction::Set, InterruptMode::Enabled, 500);
+        tim.set_input_capture(Channel::CH6, CaptureEdge::Either, InterruptMode::Disabled);
+        assert_eq!(session.read(TIM_IOS), 1 << 3);
+        assert_eq!(session.read(TIM_TCR), (TIM_TCR_OUTPUT_SET << 3) | (TIM_TCR_EDGE_EITHER << 6));
+        assert_eq!(session.read(TIM_TIE), 1 << 3);
+        assert_eq!(session.read(common::tim_tcn(3)), 500);
+        tim.set_output_action(Channel::CH3, OutputAction::Toggle);
+        assert_eq!(session.read(TIM_TCR), (TIM_TCR_OUTPUT_TOGGLE << 3) | (TIM_TCR_EDGE_EITHER << 6));
+    }
+
+    #[test]
+    fn overflow_and_reload() {
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.set_prescaler(Pre::DIV8);
+        tim.enable_overflow_interrupt();
+        tim.enable_counter_reset();
+        tim.set_reload_value(0xFFFF_0000);
+        assert_eq!(session.read(TIM_TSCR2), TIM_TSCR2_TOI_ENABLE | TIM_TSCR2_TCRE_ENABLE | TIM_TSCR2_PRE_DIV8);
+        assert_eq!(session.read(TIM_RLV), 0xFFFF_0000);
+        tim.disable_overflow_interrupt();
+        tim.disable_counter_reset();
+        assert_eq!(session.read(TIM_TSCR2), TIM_TSCR2_PRE_DIV8);
+    }
+
+    #[test]
+    fn overflow_flag_in_simulator() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_reload_value(u32::MAX - 9);
+        session.write(TIM_TCNT, u32::MAX);
+        tim.enable();
+        assert!(!tim.overflow_flag());
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert!(tim.overflow_flag());
+        assert_eq!(tim.read_count(), u32::MAX - 9);
+        tim.clear_overflow_flag();
+        assert!(!tim.overflow_flag());
+    }
+
+    #[test]
+    fn counter_reset_on_channel_7_compare() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_output_compare(Channel::CH7, OutputAction::Disconnect, InterruptMode::Disabled, 9);
+        tim.enable_counter_reset();
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(10));
+        assert_eq!(tim.read_count(), 0);
+        session.with(|sim: &mut Simulator| sim.step(25));
+        assert_eq!(tim.read_count(), 5);
+    }
+
+    #[test]
+    fn clear_interrupt_writes_only_its_channel() {
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            let session = mock::session();
+            let mut tim = TIM::new();
+            tim.clear_interrupt(channel);
+            assert_eq!(session.read(TIM_FLG1), 1 << n);
+        }
+    }
+
+    #[test]
+    fn clear_interrupt_is_write_one_to_clear() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            tim.set_output_compare(channel, OutputAction::Disconnect, InterruptMode::Enabled, n as u32 + 1);
+        }
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(8));
+        assert_eq!(tim.interrupt_flags(), TIM_FLG1_MASK);
+        let mut remaining: u32 = TIM_FLG1_MASK;
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            assert!(tim.interrupt_flag(channel));
+            tim.clear_interrupt(channel);
+            remaining &= !(1 << n);
+            assert!(!tim.interrupt_flag(channel));
+            assert_eq!(tim.interrupt_flags(), remaining);
+        }
+    }
+
+    #[test]
+    fn clear_interrupts_by_mask() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            tim.set_output_compare(channel, OutputAction::Disconnect, InterruptMode::Enabled, n as u32 + 1);
+        }
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(8));
+        tim.clear_interrupts(0b1010_0101).unwrap();
+        assert_eq!(tim.interrupt_flags(), 0b0101_1010);
+        assert_eq!(tim.clear_interrupts(0x100), Err(Error::InvalidChannelMask));
+    }
+
+    #[test]
+    fn tov_sets_and_clears_each_channel() {
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            let session = mock::session();
+            let mut tim = TIM::new();
+            session.write(TIM_TOV, 0xFF & !(1 << n));
+            tim.enable_tov(channel);
+            assert_eq!(session.read(TIM_TOV), 0xFF);
+            tim.disable_tov(channel);
+            assert_eq!(session.read(TIM_TOV), 0xFF & !(1 << n));
+        }
+    }
+
+    #[test]
+    fn tov_masks() {
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.enable_tovs(0b1100_0011).unwrap();
+        tim.disable_tovs(0b0100_0001).unwrap();
+        assert_eq!(session.read(TIM_TOV), 0b1000_0010);
+        assert_eq!(tim.enable_tovs(0x1FF), Err(Error::InvalidChannelMask));
+    }
+
+    #[test]
+    fn cf_sets_each_channel() {
+        for (n, &channel) in Channel::ALL.iter().enumerate() {
+            let session = mock::session();
+            let mut tim = TIM::new();
+            tim.enable_cf(channel);
+            assert_eq!(session.read(TIM_TCF), 1 << n);
+        }
+        let session = mock::session();
+        let mut tim = TIM::new();
+        tim.enable_cfs(0b0011_1100).unwrap();
+        assert_eq!(session.read(TIM_TCF), 0b0011_1100);
+    }
+
+    #[test]
+    fn forced_compare_applies_output_action() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        for &channel in Channel::ALL.iter() {
+            tim.set_output_compare(channel, OutputAction::Set, InterruptMode::Disabled, 0);
+        }
+        tim.enable_cf(Channel::CH0);
+        tim.enable_cf(Channel::CH7);
+        assert_eq!(session.with(|sim: &mut Simulator| sim.timer_outputs()), 0b1000_0001);
+    }
+
+    #[test]
+    fn solve_exact_frequency() {
+        let config = TIM::solve_period(Hertz(1_000)).unwrap();
+        assert_eq!(config.prescaler, Pre::DIV1);
+        assert_eq!(config.compare, 99_999);
+        assert_eq!(config.period, Duration::from_millis(1));
+        assert_eq!(config.error_ppm, 0);
+        assert_eq!(config.frequency(), Hertz(1_000));
+    }
+
+    #[test]
+    fn solve_exact_duration() {
+        let config = TIM::solve_period(Duration::from_micros(250)).unwrap();
+        assert_eq!(config.prescaler, Pre::DIV1);
+        assert_eq!(config.compare, 24_999);
+        assert_eq!(config.error_ppm, 0);
+    }
+
+    #[test]
+    fn solve_inexact_frequency_rounds_to_nearest() {
+        // 100 MHz / 3 = 33_333_333.3 cycles, DIV1 lands a third of a cycle short
+        let config = TIM::solve_period(Hertz(3)).unwrap();
+        assert_eq!(config.prescaler, Pre::DIV1);
+        assert_eq!(config.counts(), 33_333_333);
+        assert_eq!(config.period.as_ticks(), 33_333_333);
+        assert_eq!(config.error_ppm, 0);
+        // 100 MHz / 30 MHz = 3.3 cycles
+        let config = TIM::solve_period(Hertz(30_000_000)).unwrap();
+        assert_eq!(config.counts(), 3);
+        assert_eq!(config.error_ppm, -100_000);
+    }
+
+    #[test]
+    fn solve_long_period_needs_prescaler() {
+        // 200 s is 2e10 cycles, beyond 2^32 counts until DIV8
+        let config = TIM::solve_period(Duration::from_secs(200)).unwrap();
+        assert_eq!(config.prescaler, Pre::DIV8);
+        assert_eq!(config.counts(), 2_500_000_000);
+        assert_eq!(config.period, Duration::from_secs(200));
+    }
+
+    #[test]
+    fn solve_out_of_range() {
+        assert_eq!(TIM::solve_period(Hertz(0)), Err(Error::FrequencyOutOfRange));
+        assert_eq!(TIM::solve_period(Hertz(common::CHIP_FREQ + 1)), Err(Error::FrequencyOutOfRange));
+        assert_eq!(TIM::solve_period(Duration::ZERO), Err(Error::InvalidPeriod));
+        assert_eq!(TIM::solve_period(Duration::from_secs(10_000)), Err(Error::InvalidPeriod));
+    }
+
+    #[test]
+    fn set_period_repeats_on_simulator() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_output_compare(Channel::CH7, OutputAction::Toggle, InterruptMode::Enabled, 0);
+        let config = tim.set_period(Hertz(1_000_000)).unwrap();
+        assert_eq!(config.compare, 99);
+        assert_eq!(session.read(common::tim_tcn(7)), 99);
+        assert_eq!(session.read(TIM_TSCR2) & TIM_TSCR2_TCRE_ENABLE, TIM_TSCR2_TCRE_ENABLE);
+        tim.enable();
+        // TC7 matches on the tick that brings TCNT to 99 and the tick after it
+        // resets TCNT to 0, so TCNT runs 0..=99 and matches every 100 ticks
+        for _ in 0..3 {
+            session.with(|sim: &mut Simulator| sim.step(98));
+            assert_eq!(tim.read_count(), 98);
+            assert!(!tim.interrupt_flag(Channel::CH7));
+            session.with(|sim: &mut Simulator| sim.step(1));
+            assert_eq!(tim.read_count(), 99);
+            assert!(tim.interrupt_flag(Channel::CH7));
+            tim.clear_interrupt(Channel::CH7);
+            session.with(|sim: &mut Simulator| sim.step(1));
+            assert_eq!(tim.read_count(), 0);
+            assert!(!tim.interrupt_flag(Channel::CH7));
+        }
+    }
+
+    #[test]
+    fn split_channels_write_only_their_own_bits() {
+        let session = mock::session();
+        let mut parts = TIM::new().split();
+        parts.ch2.set_output_compare(OutputAction::Set, InterruptMode::Enabled, 300);
+        parts.ch6.set_input_capture(CaptureEdge::Falling, InterruptMode::Disabled);
+        parts.ch2.enable_tov();
+        assert_eq!(parts.ch6.channel(), Channel::CH6);
+        assert_eq!(session.read(TIM_IOS), 1 << 2);
+        assert_eq!(session.read(TIM_TCR), (TIM_TCR_OUTPUT_SET << 2) | (TIM_TCR_EDGE_FALLING << 6));
+        assert_eq!(session.read(TIM_TIE), 1 << 2);
+        assert_eq!(session.read(TIM_TOV), 1 << 2);
+        assert_eq!(session.read(common::tim_tcn(2)), 300);
+        parts.ch6.force_compare();
+        assert_eq!(session.read(TIM_TCF), 1 << 6);
+        parts.counter.set_prescaler(Pre::DIV8);
+        parts.counter.start_free_running();
+        assert_eq!(parts.ch2.get_prescaler(), Pre::DIV8);
+        assert_eq!(session.read(TIM_TSCR), TIM_TSCR_ENABLE);
+        assert_eq!(session.read(TIM_TSCR2) & TIM_TSCR2_TCRE_ENABLE, 0);
+    }
+
+    #[test]
+    fn split_channels_move_to_other_contexts() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let parts = TIM::new().split();
+        let channels = vec![parts.ch0, parts.ch1, parts.ch2, parts.ch3, parts.ch4, parts.ch5, parts.ch6, parts.ch7];
+        let workers: Vec<_> = channels.into_iter().map(|ch| std::thread::spawn(move || {
+            for _ in 0..100 {
+                ch.set_output_compare(OutputAction::Toggle, InterruptMode::Enabled, 0);
+            }
+        })).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(session.read(TIM_IOS), 0xFF);
+        assert_eq!(session.read(TIM_TIE), 0xFF);
+    }
+
+    #[test]
+    fn timeline_extends_count_across_wraps() {
+        let mut timeline = Timeline::new(u32::MAX - 10);
+        let start: u64 = timeline.extend(u32::MAX - 10);
+        assert_eq!(timeline.update(5) - start, 16);
+        assert_eq!(timeline.extend(u32::MAX - 2), start + 8);
+        assert_eq!(timeline.extend(3), start + 14);
+        assert_eq!(timeline.update(u32::MAX) - start, (1 << 32) + 10);
+        // Values latched before the first read still come out positive
+        assert_eq!(Timeline::new(0).extend(u32::MAX), u32::MAX as u64);
+    }
+}
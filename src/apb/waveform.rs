@@ -0,0 +1,591 @@
+use core::cell::Cell;
+use core::num::NonZeroU32;
+use critical_section::Mutex;
+use crate::apb::timer::{InterruptMode, OutputAction, TIMChannel};
+use crate::common::{self, Error};
+use crate::time::{Duration, Hertz};
+
+// Compares less than this many ticks ahead of TCNT are still to come
+const WAVEFORM_HALF_RANGE: u32 = 1 << 31;
+
+#[derive(Clone, Copy)]
+struct WaveState {
+    active: bool,
+    compare: u32,
+    high: u32,
+    low: u32,
+    // Pulses left to generate, None runs until stopped
+    remaining: Option<u32>,
+    // Action the pending compare will apply
+    action: OutputAction,
+}
+
+impl WaveState {
+    const IDLE: WaveState = WaveState {
+        active: false,
+        compare: 0,
+        high: 0,
+        low: 0,
+        remaining: None,
+        action: OutputAction::Disconnect,
+    };
+}
+
+// Fixed frequency PWM view of a waveform channel for use with embedded-hal
+// drivers. Edges still come from poll(), which must keep running.
+pub struct OutputRef<'a> {
+    waveform: &'a Waveform,
+    period: u32
+}
+
+// Generates square waves, single pulses and pulse trains on one TIM output
+// compare channel. The counter must be free running (see
+// TIMCounter::start_free_running) and each compare moves TCn on by the next
+// high or low time, so poll() has to run (normally from the TIM interrupt)
+// before the counter reaches the following edge. A compare TCNT has already
+// passed would only match a full counter wrap later, so its edge is forced at
+// once and the waveform goes on from there. The state sits behind a critical
+// section, so main code can change the waveform through &self while an
+// interrupt handler polls it.
+pub struct Waveform {
+    ch: TIMChannel,
+    state: Mutex<Cell<WaveState>>,
+}
+
+impl Waveform {
+    pub fn new(ch: TIMChannel) -> Waveform {
+        Waveform { ch, state: Mutex::new(Cell::new(WaveState::IDLE)) }
+    }
+
+    // Continuous square wave with a 50% duty cycle, starting low
+    pub fn square_wave(&self, frequency: Hertz) -> Result<(), Error> {
+        let period: u32 = self.period(frequency)?;
+        let high: u32 = period / 2;
+        self.start_ticks(period - high, high, period - high, None)
+    }
+
+    // One high pulse of the given width once delay has passed
+    pub fn single_pulse(&self, delay: Duration, width: Duration) -> Result<(), Error> {
+        // A single pulse never waits out a low time
+        self.start_ticks(self.ticks(delay)?, self.ticks(width)?, 1, NonZeroU32::new(1))
+    }
+
+    // A number of high pulses, the first one after delay
+    pub fn pulse_train(&self, delay: Duration, high: Duration, low: Duration, pulses: NonZeroU32) -> Result<(), Error> {
+        let low: u32 = if pulses.get() == 1 { 1 } else { self.ticks(low)? };
+        self.start_ticks(self.ticks(delay)?, self.ticks(high)?, low, Some(pulses))
+    }
+
+    // High pulses that repeat until the channel is stopped, the first one after delay
+    pub fn continuous(&self, delay: Duration, high: Duration, low: Duration) -> Result<(), Error> {
+        self.start_ticks(self.ticks(delay)?, self.ticks(high)?, self.ticks(low)?, None)
+    }
+
+    // Like pulse_train, or continuous when pulses is None, with times given in
+    // counter ticks. A time of zero would put the next compare a whole counter
+    // wrap away, so every time must be at least one. Times too short to
+    // schedule after a late compare stop the channel with FrequencyOutOfRange.
+    pub fn start_ticks(&self, delay: u32, high: u32, low: u32, pulses: Option<NonZeroU32>) -> Result<(), Error> {
+        if delay == 0 || high == 0 || low == 0 {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        critical_section::with(|cs| {
+            let compare: u32 = self.ch.read_count().wrapping_add(delay);
+            self.ch.clear_interrupt();
+            self.ch.set_output_compare(OutputAction::Set, InterruptMode::Enabled, compare);
+            let mut state: WaveState = WaveState {
+                active: true,
+                compare,
+                high,
+                low,
+                remaining: pulses.map(NonZeroU32::get),
+                action: OutputAction::Set,
+            };
+            let result: Result<bool, Error> = match self.passed(compare) {
+                Some(count) => self.catch_up(&mut state, count),
+                None => Ok(false),
+            };
+            self.state.borrow(cs).set(state);
+            result.map(|_| ())
+        })
+    }
+
+    // Changes the high and low times of a running continuous train from its
+    // next edge on, or starts a continuous one if the channel is idle
+    pub fn set_pulse_ticks(&self, high: u32, low: u32) -> Result<(), Error> {
+        if high == 0 || low == 0 {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        critical_section::with(|cs| {
+            let mut state: WaveState = self.state.borrow(cs).get();
+            if state.active && state.remaining.is_none() {
+                state.high = high;
+                state.low = low;
+                self.state.borrow(cs).set(state);
+                Ok(())
+            }
+            else {
+                self.start_ticks(low, high, low, None)
+            }
+        })
+    }
+
+    // Holds the output high until the channel is stopped or restarted
+    pub fn hold_high(&self) {
+        self.force(OutputAction::Set);
+    }
+
+    pub fn output(&self, frequency: Hertz) -> Result<OutputRef<'_>, Error> {
+        let period: u32 = self.period(frequency)?;
+        Ok(OutputRef { waveform: self, period })
+    }
+
+    // Ends the waveform and forces the output low
+    pub fn stop(&self) {
+        self.force(OutputAction::Clear);
+    }
+
+    pub fn is_active(&self) -> bool {
+        critical_section::with(|cs| self.state.borrow(cs).get().active)
+    }
+
+    // Schedules the next edge if the compare matched and returns true when the
+    // waveform finished, or was stopped because its times are too short
+    pub fn poll(&self) -> bool {
+        critical_section::with(|cs| {
+            let mut state: WaveState = self.state.borrow(cs).get();
+            if !state.active || !self.ch.interrupt_flag() {
+                return false;
+            }
+            self.ch.clear_interrupt();
+            let finished: bool = self.advance(&mut state) != Ok(false);
+            self.state.borrow(cs).set(state);
+            finished
+        })
+    }
+
+    pub fn channel(&self) -> &TIMChannel {
+        &self.ch
+    }
+
+    pub fn free(self) -> TIMChannel {
+        if self.is_active() {
+            self.stop();
+        }
+        self.ch
+    }
+
+    // Idles the channel after applying an action through a forced compare
+    fn force(&self, action: OutputAction) {
+        critical_section::with(|cs| {
+            self.ch.set_interrupt_mode(InterruptMode::Disabled);
+            self.ch.set_output_action(action);
+            self.ch.force_compare();
+            self.ch.set_output_action(OutputAction::Disconnect);
+            self.ch.clear_interrupt();
+            self.state.borrow(cs).set(WaveState::IDLE);
+        });
+    }
+
+    // Moves past the edge that just happened and schedules the next one.
+    // Returns true when the waveform is done.
+    fn advance(&self, state: &mut WaveState) -> Result<bool, Error> {
+        if self.next_edge(state) {
+            return Ok(true);
+        }
+        match self.passed(state.compare) {
+            Some(count) => self.catch_up(state, count),
+            None => Ok(false),
+        }
+    }
+
+    // Forces the edge of a compare TCNT passed at count and schedules the next
+    // one from count. If that one is late too the times are shorter than the
+    // driver can schedule, so the channel is stopped instead of spinning here.
+    fn catch_up(&self, state: &mut WaveState, count: u32) -> Result<bool, Error> {
+        self.ch.force_compare();
+        state.compare = count;
+        if self.next_edge(state) {
+            return Ok(true);
+        }
+        if self.passed(state.compare).is_some() {
+            self.stop();
+            *state = WaveState::IDLE;
+            return Err(Error::FrequencyOutOfRange);
+        }
+        Ok(false)
+    }
+
+    // Steps the state past one edge and writes the next compare; returns true
+    // and idles the channel once the last pulse has ended
+    fn next_edge(&self, state: &mut WaveState) -> bool {
+        match state.action {
+            OutputAction::Set => {
+                state.compare = state.compare.wrapping_add(state.high);
+                state.action = OutputAction::Clear;
+            }
+            _ => {
+                if let Some(remaining) = state.remaining.as_mut() {
+                    *remaining -= 1;
+                }
+                if state.remaining == Some(0) {
+                    *state = WaveState::IDLE;
+                    self.ch.set_interrupt_mode(InterruptMode::Disabled);
+                    self.ch.set_output_action(OutputAction::Disconnect);
+                    return true;
+                }
+                state.compare = state.compare.wrapping_add(state.low);
+                state.action = OutputAction::Set;
+            }
+        }
+        self.ch.set_output_action(state.action);
+        self.ch.set_compare_value(state.compare);
+        false
+    }
+
+    // Returns the count if TCNT is at or past compare without having matched it
+    fn passed(&self, compare: u32) -> Option<u32> {
+        let count: u32 = self.ch.read_count();
+        let ahead: u32 = compare.wrapping_sub(count);
+        if self.ch.interrupt_flag() || (ahead != 0 && ahead < WAVEFORM_HALF_RANGE) {
+            return None;
+        }
+        Some(count)
+    }
+
+    fn period(&self, frequency: Hertz) -> Result<u32, Error> {
+        if frequency.0 == 0 {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        let period: u32 = common::rounding_division(self.ch.count_frequency(), frequency.0);
+        if period < 2 {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        Ok(period)
+    }
+
+    fn ticks(&self, duration: Duration) -> Result<u32, Error> {
+        let ticks: u64 = duration.as_ticks() >> (self.ch.get_prescaler() as u32);
+        if ticks == 0 || u32::MAX as u64 <= ticks {
+            return Err(Error::FrequencyOutOfRange);
+        }
+        Ok(ticks as u32)
+    }
+}
+
+impl<'a> OutputRef<'a> {
+    pub fn max_duty_cycle(&self) -> u16 {
+        self.period.min(u16::MAX as u32) as u16
+    }
+
+    // Period in counter ticks
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+}
+
+impl<'a> embedded_hal::pwm::ErrorType for OutputRef<'a> {
+    type Error = Error;
+}
+
+impl<'a> embedded_hal::pwm::SetDutyCycle for OutputRef<'a> {
+    fn max_duty_cycle(&self) -> u16 {
+        OutputRef::max_duty_cycle(self)
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Error> {
+        let max: u64 = OutputRef::max_duty_cycle(self) as u64;
+        if duty as u64 > max {
+            return Err(Error::DutyOutOfRange);
+        }
+        let high: u32 = ((self.period as u64 * duty as u64 + max / 2) / max) as u32;
+        if high == 0 {
+            self.waveform.stop();
+        }
+        else if high >= self.period {
+            self.waveform.hold_high();
+        }
+        else {
+            self.waveform.set_pulse_ticks(high, self.period - high)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apb::capture::Capture;
+    use crate::apb::timer::{Channel, Pre, TIM, TIM_TCNT};
+    use crate::mock::{self, sim::Simulator};
+
+    struct Trace {
+        rising: u32,
+        falling: u32,
+        high_cycles: u64,
+        finished: bool,
+    }
+
+    fn level(session: &mock::Session, waveform: &Waveform) -> bool {
+        let channel: u32 = waveform.channel().channel() as u32;
+        session.with(|sim: &mut Simulator| sim.timer_outputs()) & common::timn(channel) != 0
+    }
+
+    // Steps one cycle at a time, polling like the interrupt handler would
+    fn run(session: &mock::Session, waveform: &Waveform, cycles: u64) -> Trace {
+        let mut trace = Trace { rising: 0, falling: 0, high_cycles: 0, finished: false };
+        let mut was_high: bool = level(session, waveform);
+        for _ in 0..cycles {
+            session.with(|sim: &mut Simulator| sim.step(1));
+            trace.finished |= waveform.poll();
+            let high: bool = level(session, waveform);
+            match (was_high, high) {
+                (false, true) => trace.rising += 1,
+                (true, false) => trace.falling += 1,
+                _ => (),
+            }
+            if high { trace.high_cycles += 1; }
+            was_high = high;
+        }
+        trace
+    }
+
+    // One waveform per channel on a free running counter
+    fn waveforms(session: &mock::Session, pre_div: Pre) -> Vec<Waveform> {
+        session.install(Simulator::new());
+        let mut parts = TIM::new().split();
+        parts.counter.set_prescaler(pre_div);
+        parts.counter.start_free_running();
+        vec![parts.ch0, parts.ch1, parts.ch2, parts.ch3, parts.ch4, parts.ch5, parts.ch6, parts.ch7]
+            .into_iter()
+            .map(Waveform::new)
+            .collect()
+    }
+
+    #[test]
+    fn square_wave_on_every_channel() {
+        for n in 0..8 {
+            let session = mock::session();
+            let waveforms = waveforms(&session, Pre::DIV1);
+            waveforms[n].square_wave(Hertz(1_000_000)).unwrap();
+            let trace = run(&session, &waveforms[n], 1_000);
+            assert_eq!(trace.rising, 10);
+            assert_eq!(trace.falling, 10);
+            assert!(waveforms[n].is_active());
+        }
+    }
+
+    #[test]
+    fn square_waves_are_independent() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        waveforms[1].square_wave(Hertz(1_000_000)).unwrap();
+        waveforms[6].square_wave(Hertz(2_500_000)).unwrap();
+        let mut slow_high: u32 = 0;
+        let mut fast_high: u32 = 0;
+        for _ in 0..2_000 {
+            session.with(|sim: &mut Simulator| sim.step(1));
+            waveforms[1].poll();
+            waveforms[6].poll();
+            slow_high += level(&session, &waveforms[1]) as u32;
+            fast_high += level(&session, &waveforms[6]) as u32;
+        }
+        assert_eq!(slow_high, 1_000);
+        assert_eq!(fast_high, 1_000);
+        waveforms[1].stop();
+        let fast = run(&session, &waveforms[6], 2_000);
+        assert_eq!(fast.rising, 50);
+        assert_eq!(fast.high_cycles, 1_000);
+    }
+
+    #[test]
+    fn single_pulse() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[3];
+        waveform.single_pulse(Duration::from_ticks(30), Duration::from_ticks(20)).unwrap();
+        let before = run(&session, waveform, 29);
+        assert_eq!(before.rising, 0);
+        let trace = run(&session, waveform, 200);
+        assert_eq!(trace.rising, 1);
+        assert_eq!(trace.falling, 1);
+        assert_eq!(trace.high_cycles, 20);
+        assert!(trace.finished);
+        assert!(!waveform.is_active());
+    }
+
+    #[test]
+    fn pulse_train_stops_after_count() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[5];
+        let ten: Duration = Duration::from_ticks(10);
+        waveform.pulse_train(ten, ten, Duration::from_ticks(15), NonZeroU32::new(3).unwrap()).unwrap();
+        let trace = run(&session, waveform, 500);
+        assert_eq!(trace.rising, 3);
+        assert_eq!(trace.falling, 3);
+        assert_eq!(trace.high_cycles, 30);
+        assert!(trace.finished);
+    }
+
+    #[test]
+    fn continuous_runs_until_stopped() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[2];
+        waveform.continuous(Duration::from_ticks(5), Duration::from_ticks(10), Duration::from_ticks(30)).unwrap();
+        let trace = run(&session, waveform, 4_004);
+        assert_eq!(trace.rising, 100);
+        assert_eq!(trace.high_cycles, 1_000);
+        assert!(!trace.finished);
+        assert!(waveform.is_active());
+    }
+
+    #[test]
+    fn times_scale_with_prescaler() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV4);
+        waveforms[0].single_pulse(Duration::from_ticks(40), Duration::from_ticks(80)).unwrap();
+        let trace = run(&session, &waveforms[0], 400);
+        assert_eq!(trace.high_cycles, 80);
+    }
+
+    #[test]
+    fn stop_drives_output_low() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[2];
+        waveform.square_wave(Hertz(1_000_000)).unwrap();
+        run(&session, waveform, 75);
+        assert!(level(&session, waveform));
+        waveform.stop();
+        assert!(!level(&session, waveform));
+        let trace = run(&session, waveform, 200);
+        assert_eq!(trace.rising, 0);
+    }
+
+    #[test]
+    fn late_poll_forces_the_missed_edge() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[4];
+        waveform.square_wave(Hertz(1_000_000)).unwrap();
+        run(&session, waveform, 49);
+        assert!(!level(&session, waveform));
+        // The rising edge is polled only after its falling edge was due
+        session.with(|sim: &mut Simulator| sim.step(99));
+        assert!(level(&session, waveform));
+        assert!(!waveform.poll());
+        assert!(!level(&session, waveform));
+        // Without the forced edge TC4 would sit behind TCNT for a whole wrap
+        let trace = run(&session, waveform, 1_000);
+        assert_eq!(trace.rising, 10);
+        assert_eq!(trace.high_cycles, 500);
+    }
+
+    #[test]
+    fn late_start_fires_at_once() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[1];
+        // TCNT moves past the first compare while it is being written
+        session.with(|sim: &mut Simulator| sim.on_read(TIM_TCNT, Box::new(|sim: &mut Simulator| sim.step(20))));
+        waveform.start_ticks(5, 40, 60, None).unwrap();
+        assert!(level(&session, waveform));
+        let trace = run(&session, waveform, 1_000);
+        assert_eq!(trace.rising, 10);
+        assert_eq!(trace.high_cycles, 400);
+    }
+
+    #[test]
+    fn times_shorter_than_the_driver_stop_the_channel() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[7];
+        session.with(|sim: &mut Simulator| sim.set_read_latency(5));
+        assert_eq!(waveform.start_ticks(1, 1, 1, None), Err(Error::FrequencyOutOfRange));
+        assert!(!waveform.is_active());
+        assert!(!level(&session, waveform));
+    }
+
+    #[test]
+    fn out_of_range_times() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[0];
+        assert_eq!(waveform.square_wave(Hertz(0)), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.square_wave(Hertz(common::CHIP_FREQ)), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.single_pulse(Duration::ZERO, Duration::from_ticks(1)), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.single_pulse(Duration::from_secs(60), Duration::from_ticks(1)), Err(Error::FrequencyOutOfRange));
+        assert!(!waveform.is_active());
+    }
+
+    #[test]
+    fn zero_ticks_are_rejected() {
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[1];
+        assert_eq!(waveform.start_ticks(0, 10, 10, None), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.start_ticks(10, 0, 10, None), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.start_ticks(10, 10, 0, None), Err(Error::FrequencyOutOfRange));
+        assert_eq!(waveform.set_pulse_ticks(0, 10), Err(Error::FrequencyOutOfRange));
+        assert!(!waveform.is_active());
+        waveform.start_ticks(10, 10, 10, None).unwrap();
+        assert_eq!(waveform.set_pulse_ticks(10, 0), Err(Error::FrequencyOutOfRange));
+        assert!(waveform.is_active());
+    }
+
+    #[test]
+    fn output_set_duty_cycle() {
+        use embedded_hal::pwm::SetDutyCycle;
+        let session = mock::session();
+        let waveforms = waveforms(&session, Pre::DIV1);
+        let waveform = &waveforms[4];
+        let mut output = waveform.output(Hertz(1_000_000)).unwrap();
+        assert_eq!(SetDutyCycle::max_duty_cycle(&output), 100);
+        output.set_duty_cycle(30).unwrap();
+        // The output and poll() share the waveform, as they would with an interrupt handler
+        run(&session, waveform, 100);
+        let trace = run(&session, waveform, 1_000);
+        assert_eq!(trace.rising, 10);
+        assert_eq!(trace.high_cycles, 300);
+        // A new duty takes over from the next edge without a restart
+        output.set_duty_cycle_percent(80).unwrap();
+        run(&session, waveform, 100);
+        let trace = run(&session, waveform, 1_000);
+        assert_eq!(trace.high_cycles, 800);
+        output.set_duty_cycle_fully_on().unwrap();
+        let trace = run(&session, waveform, 500);
+        assert_eq!(trace.high_cycles, 500);
+        output.set_duty_cycle_fully_off().unwrap();
+        let trace = run(&session, waveform, 500);
+        assert_eq!(trace.high_cycles, 0);
+        assert_eq!(output.set_duty_cycle(101), Err(Error::DutyOutOfRange));
+    }
+
+    #[test]
+    fn waveform_and_capture_share_a_timer() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut parts = TIM::new().split();
+        parts.counter.start_free_running();
+        let waveform = Waveform::new(parts.ch6);
+        let mut capture = Capture::new(parts.ch0);
+        waveform.square_wave(Hertz(1_000_000)).unwrap();
+        capture.start();
+        // Channel 6 is looped back into channel 0
+        for _ in 0..1_000 {
+            session.with(|sim: &mut Simulator| {
+                sim.step(1);
+                let high: bool = sim.timer_outputs() & common::timn(6) != 0;
+                sim.set_timer_input(0, high);
+            });
+            waveform.poll();
+            capture.poll();
+        }
+        let measurement = capture.measurement().unwrap();
+        assert_eq!(measurement.period_ticks, 100);
+        assert_eq!(measurement.high_ticks, 50);
+        assert_eq!(waveform.channel().channel(), Channel::CH6);
+    }
+}
@@ -11,11 +11,19 @@ pub fn pwmn(channel: u32) -> u32 {
     channel * 0x0C
 }
 
+pub fn plicn(source: u32) -> u32 {
+    1 << (source - 1)
+}
+
 pub fn rounding_division(dividend: u32, divisor: u32) -> u32 {
     if divisor == 0 { return 0; }
     (dividend + (divisor / 2)) / divisor
 }
 
+pub fn timn(channel: u32) -> u32 {
+    1 << channel
+}
+
 pub fn tim_tcn(channel: u32) -> u32 {
     0x80020000 + 0x28 + (0x4 * channel)
 }
@@ -38,4 +46,22 @@ pub fn tim_tcr_output_mask(channel: u32) -> u32 {
 
 pub fn tim_tie_mask(channel: u32) -> u32 {
     1 << channel
-}
\ No newline at end of file
+}
+
+// Errors returned by the configuration APIs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    FrequencyOutOfRange,
+    DutyOutOfRange,
+    InvalidChannelMask,
+    UnsupportedMode,
+    InvalidSource,
+    InvalidPeriod,
+    Busy,
+}
+
+impl embedded_hal::pwm::Error for Error {
+    fn kind(&self) -> embedded_hal::pwm::ErrorKind {
+        embedded_hal::pwm::ErrorKind::Other
+    }
+}
@@ -0,0 +1,144 @@
+use crate::ahb::clint::{CLINT, MTime};
+use crate::apb::timer::TIM;
+use crate::common::{CHIP_FREQ};
+use crate::time::{Duration};
+
+pub enum DelaySource {
+    CLINT(MTime),
+    TIM(TIM),
+}
+
+pub struct Delay {
+    source: DelaySource
+}
+
+impl Delay {
+    // Only reads mtime, so the CLINT stays with its owner
+    pub fn from_clint(clint: &CLINT) -> Delay {
+        Delay { source: DelaySource::CLINT(clint.mtime()) }
+    }
+
+    // The timer is enabled and keeps its current prescaler. Counter reset and
+    // the reload value are cleared so TCNT runs through all 32 bits.
+    pub fn from_tim(mut tim: TIM) -> Delay {
+        tim.disable_counter_reset();
+        tim.set_reload_value(0);
+        tim.enable();
+        Delay { source: DelaySource::TIM(tim) }
+    }
+
+    // Busy waits for a number of CHIP_FREQ clock cycles
+    pub fn delay_cycles(&mut self, cycles: u64) {
+        match &mut self.source {
+            DelaySource::CLINT(mtime) => {
+                let start: u64 = mtime.now();
+                while mtime.now().wrapping_sub(start) < cycles {}
+            }
+            DelaySource::TIM(tim) => {
+                // Accumulate counter deltas so wraps of TCNT are tolerated
+                // The first tick can come one cycle after the start, so wait
+                // for it plus enough whole ticks to cover the rest
+                let shift: u32 = tim.get_prescaler() as u32;
+                let ticks: u64 = if cycles == 0 { 0 } else { ((cycles - 1).saturating_add((1 << shift) - 1) >> shift) + 1 };
+                let mut elapsed: u64 = 0;
+                let mut last: u32 = tim.read_count();
+                while elapsed < ticks {
+                    let curr: u32 = tim.read_count();
+                    elapsed += curr.wrapping_sub(last) as u64;
+                    last = curr;
+                }
+            }
+        }
+    }
+
+    pub fn delay(&mut self, duration: Duration) {
+        self.delay_cycles(duration.as_ticks());
+    }
+
+    pub fn delay_us(&mut self, us: u32) {
+        self.delay_cycles((us as u64) * ((CHIP_FREQ / 1_000_000) as u64));
+    }
+
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay_cycles((ms as u64) * ((CHIP_FREQ / 1_000) as u64));
+    }
+
+    pub fn free(self) -> DelaySource {
+        self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apb::timer::{Pre, TIM_RLV, TIM_TCNT, TIM_TSCR2, TIM_TSCR2_TCRE_ENABLE};
+    use crate::mock::{self, sim::Simulator};
+
+    // Cycles that pass while delay_cycles(cycles) runs, with every register read taking latency cycles
+    fn measure(session: &mock::Session, delay: &mut Delay, cycles: u64, latency: u64) -> u64 {
+        let start: u64 = session.with(|sim: &mut Simulator| {
+            sim.set_read_latency(latency);
+            sim.mtime()
+        });
+        delay.delay_cycles(cycles);
+        session.with(|sim: &mut Simulator| {
+            sim.set_read_latency(0);
+            sim.mtime() - start
+        })
+    }
+
+    #[test]
+    fn clint_delay() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let clint = CLINT::new();
+        let mut delay = Delay::from_clint(&clint);
+        // Each mtime read is three register reads, and the delay may overshoot by two
+        let elapsed: u64 = measure(&session, &mut delay, 500, 10);
+        assert!((500..=560).contains(&elapsed), "{}", elapsed);
+        assert!(measure(&session, &mut delay, 0, 10) <= 60);
+    }
+
+    #[test]
+    fn tim_delay() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut delay = Delay::from_tim(TIM::new());
+        let elapsed: u64 = measure(&session, &mut delay, 1_000, 7);
+        assert!((1_000..=1_021).contains(&elapsed), "{}", elapsed);
+    }
+
+    #[test]
+    fn prescaled_delay_rounds_up() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_prescaler(Pre::DIV8);
+        let mut delay = Delay::from_tim(tim);
+        // Every prescaler phase, as the delay can start anywhere within a tick
+        for phase in 0..8 {
+            session.with(|sim: &mut Simulator| sim.step(phase));
+            for cycles in [1, 8, 20] {
+                let elapsed: u64 = measure(&session, &mut delay, cycles, 1);
+                assert!(elapsed >= cycles, "{} cycles took {}", cycles, elapsed);
+                assert!(elapsed <= cycles + 16, "{} cycles took {}", cycles, elapsed);
+            }
+        }
+    }
+
+    #[test]
+    fn tim_delay_across_counter_wrap() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.enable_counter_reset();
+        tim.set_reload_value(0xFFFF_0000);
+        let mut delay = Delay::from_tim(tim);
+        assert_eq!(session.read(TIM_TSCR2) & TIM_TSCR2_TCRE_ENABLE, 0);
+        assert_eq!(session.read(TIM_RLV), 0);
+        session.write(TIM_TCNT, u32::MAX - 100);
+        let elapsed: u64 = measure(&session, &mut delay, 1_000, 5);
+        assert!((1_000..=1_015).contains(&elapsed), "{}", elapsed);
+        assert!(session.read(TIM_TCNT) < 1_000);
+    }
+}
@@ -1,5 +1,18 @@
-#![no_std]
+#![cfg_attr(not(any(test, feature = "mock")), no_std)]
+// Drivers are singletons, so a Default that claims the hardware would mislead
+#![allow(clippy::new_without_default)]
 
 pub mod ahb;
 pub mod apb;
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod delay;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod peripherals;
+mod register;
+pub mod servo;
+#[cfg(all(feature = "critical-section-single-hart", target_arch = "riscv32", not(feature = "mock")))]
+mod single_hart;
+pub mod time;
+
+pub use peripherals::Peripherals;
@@ -0,0 +1,126 @@
+use crate::peripherals;
+use std::any::Any;
+use std::boxed::Box;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+pub mod sim;
+
+// Mock Constants
+pub const MOCK_REGION_SIZE: usize = 0x400;
+pub const MOCK_REGIONS: [u32; 5] = [
+    0x8000_0004, // GPIO
+    0x8001_0000, // PWM
+    0x8002_0000, // TIM
+    0xE000_0000, // CLINT
+    0xE001_0000, // PLIC
+];
+
+// Host memory standing in for each register block. It is never read or
+// written; only its address is used to recover the physical register address.
+static mut ANCHORS: [[u32; MOCK_REGION_SIZE / 4]; 5] = [[0; MOCK_REGION_SIZE / 4]; 5];
+
+static BACKEND: Mutex<Option<Box<dyn Backend>>> = Mutex::new(None);
+static SESSION: Mutex<()> = Mutex::new(());
+
+// Receives every register access made by the drivers, keyed by physical address
+pub trait Backend: AsAny + Send + 'static {
+    fn read(&mut self, address: u32) -> u32;
+    fn write(&mut self, address: u32, value: u32);
+}
+
+// Lets Session::with() downcast the installed backend without trait upcasting
+pub trait AsAny {
+    fn as_any(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Plain register file: reads return the last value written, or zero
+#[derive(Default)]
+pub struct Memory {
+    registers: BTreeMap<u32, u32>
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory { registers: BTreeMap::new() }
+    }
+}
+
+impl Backend for Memory {
+    fn read(&mut self, address: u32) -> u32 {
+        *self.registers.get(&address).unwrap_or(&0)
+    }
+
+    fn write(&mut self, address: u32, value: u32) {
+        self.registers.insert(address, value);
+    }
+}
+
+// Holds the mock exclusively so tests running on other threads cannot interfere
+pub struct Session {
+    _guard: MutexGuard<'static, ()>
+}
+
+// Starts a session with fresh Memory and every peripheral constructible again
+pub fn session() -> Session {
+    let guard = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+    install_backend(Memory::new());
+    peripherals::release(peripherals::ALL_TAKEN);
+    Session { _guard: guard }
+}
+
+impl Session {
+    pub fn install<B: Backend>(&self, backend: B) {
+        install_backend(backend);
+    }
+
+    // Runs f on the installed backend, which must be of type B
+    pub fn with<B: Backend, R>(&self, f: impl FnOnce(&mut B) -> R) -> R {
+        let mut guard = lock_backend();
+        let backend: &mut dyn Backend = guard.as_mut().expect("No mock backend installed.").as_mut();
+        let any: &mut dyn Any = backend.as_any();
+        f(any.downcast_mut::<B>().expect("Installed mock backend is of a different type."))
+    }
+
+    pub fn read(&self, address: u32) -> u32 {
+        read(address)
+    }
+
+    pub fn write(&self, address: u32, value: u32) {
+        write(address, value)
+    }
+}
+
+fn install_backend<B: Backend>(backend: B) {
+    *lock_backend() = Some(Box::new(backend));
+}
+
+fn lock_backend() -> MutexGuard<'static, Option<Box<dyn Backend>>> {
+    BACKEND.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn read(address: u32) -> u32 {
+    lock_backend().get_or_insert_with(|| Box::new(Memory::new())).read(address)
+}
+
+pub fn write(address: u32, value: u32) {
+    lock_backend().get_or_insert_with(|| Box::new(Memory::new())).write(address, value)
+}
+
+// Raw pointer into ANCHORS; no reference to the static is ever created
+pub fn host_address(address: u32) -> *mut u8 {
+    let region: usize = MOCK_REGIONS.iter().position(|&base| base == address)
+        .expect("No mock region at this address.");
+    unsafe { (core::ptr::addr_of_mut!(ANCHORS) as *mut u8).add(region * MOCK_REGION_SIZE) }
+}
+
+pub fn physical_address(host: usize) -> u32 {
+    let offset: usize = host - host_address(MOCK_REGIONS[0]) as usize;
+    MOCK_REGIONS[offset / MOCK_REGION_SIZE] + (offset % MOCK_REGION_SIZE) as u32
+}
@@ -0,0 +1,608 @@
+use crate::ahb::clint::*;
+use crate::ahb::plic::*;
+use crate::apb::gpio::*;
+use crate::apb::pwm::*;
+use crate::apb::timer::*;
+use crate::common;
+use crate::mock::{Backend};
+use std::collections::BTreeMap;
+
+// Behavioural model of the AFTx06 peripherals, advanced a number of clock
+// cycles at a time by step(). Registers without side effects are kept in a
+// plain map.
+pub struct Simulator {
+    registers: BTreeMap<u32, u32>,
+    gpio_inputs: u32,
+    timer_inputs: u32,
+    timer_outputs: u32,
+    timer_prescale: u32,
+    pwm_counters: [u32; PWM_CHANNELS],
+    mtime: u64,
+    external_lines: u32,
+    plic_pending: u32,
+    plic_in_service: u32,
+    wiring: [Option<InterruptSource>; 3],
+    read_hook: Option<(u32, ReadHook)>,
+    read_latency: u64,
+}
+
+// Interrupt outputs of the modelled peripherals. The PLIC source each one
+// drives is not documented in this crate, so a test wires them up with
+// Simulator::connect_interrupt() and they stay unconnected until then.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptLine {
+    Gpio =        0,   // GPIO intr_sts, any pin
+    Tim =         1,   // TIM tflg1, any channel with its interrupt enabled
+    TimOverflow = 2,   // TIM tflg2 with the overflow interrupt enabled
+}
+
+// Runs once, right after the register it is attached to has been read
+pub type ReadHook = Box<dyn FnOnce(&mut Simulator) + Send>;
+
+impl Default for Simulator {
+    fn default() -> Simulator {
+        Simulator::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        let mut sim = Simulator {
+            registers: BTreeMap::new(),
+            gpio_inputs: 0,
+            timer_inputs: 0,
+            timer_outputs: 0,
+            timer_prescale: 0,
+            pwm_counters: [0; PWM_CHANNELS],
+            mtime: 0,
+            external_lines: 0,
+            plic_pending: 0,
+            plic_in_service: 0,
+            wiring: [None; 3],
+            read_hook: None,
+            read_latency: 0,
+        };
+        sim.set(CLINT_MTIMECMP, u32::MAX);
+        sim.set(CLINT_MTIMECMP + 0x04, u32::MAX);
+        sim
+    }
+
+    fn reg(&self, address: u32) -> u32 {
+        *self.registers.get(&address).unwrap_or(&0)
+    }
+
+    fn set(&mut self, address: u32, value: u32) {
+        self.registers.insert(address, value);
+    }
+
+    // Lets a test change the hardware between two register reads of a driver
+    pub fn on_read(&mut self, address: u32, hook: ReadHook) {
+        self.read_hook = Some((address, hook));
+    }
+
+    // Steps the model by cycles after every register read, so a driver that
+    // busy waits on a register sees time pass
+    pub fn set_read_latency(&mut self, cycles: u64) {
+        self.read_latency = cycles;
+    }
+
+    // Advances every peripheral by a number of CHIP_FREQ clock cycles
+    pub fn step(&mut self, cycles: u64) {
+        self.mtime = self.mtime.wrapping_add(cycles);
+        for channel in 0..PWM_CHANNELS {
+            let period: u32 = self.reg(PWM_PERIOD + common::pwmn(channel as u32));
+            if period != 0 {
+                let counter: u64 = self.pwm_counters[channel] as u64 + cycles;
+                self.pwm_counters[channel] = (counter % (period as u64)) as u32;
+            }
+        }
+        if self.reg(TIM_TSCR) & TIM_TSCR_ENABLE != 0 {
+            let divider: u64 = 1 << (self.reg(TIM_TSCR2) & TIM_TSCR2_PRE_MASK);
+            let phase: u64 = (self.timer_prescale as u64).min(divider - 1) + cycles;
+            self.timer_prescale = (phase % divider) as u32;
+            self.timer_advance(phase / divider);
+        }
+        self.update_plic();
+    }
+
+    // GPIO
+
+    pub fn set_gpio_input(&mut self, pin: u32, high: bool) {
+        let mask: u32 = common::gpion(pin);
+        let old: u32 = self.gpio_inputs;
+        if high { self.gpio_inputs |= mask; } else { self.gpio_inputs &= !mask; }
+        let inputs: u32 = !self.reg(GPIO_DATA_DIRECTION);
+        let rising: u32 = !old & self.gpio_inputs & inputs;
+        let falling: u32 = old & !self.gpio_inputs & inputs;
+        let edges: u32 = (rising & self.reg(GPIO_POSITIVE_EDGE))
+            | (falling & self.reg(GPIO_NEGATIVE_EDGE));
+        let status: u32 = self.reg(GPIO_INTERRUPT_STATUS);
+        self.set(GPIO_INTERRUPT_STATUS, status | (edges & self.reg(GPIO_INTERRUPT_ENABLE)));
+        self.update_plic();
+    }
+
+    // Levels currently driven by the pins configured as outputs
+    pub fn gpio_outputs(&self) -> u32 {
+        self.reg(GPIO_DATA) & self.reg(GPIO_DATA_DIRECTION)
+    }
+
+    // TIM
+
+    pub fn set_timer_input(&mut self, channel: u32, high: bool) {
+        let mask: u32 = common::tim_ios_output(channel);
+        let old: bool = self.timer_inputs & mask != 0;
+        if high { self.timer_inputs |= mask; } else { self.timer_inputs &= !mask; }
+        if self.reg(TIM_IOS) & mask != 0 || old == high {
+            return;
+        }
+        let tcr: u32 = self.reg(TIM_TCR);
+        let falling: bool = tcr & (TIM_TCR_EDGE_FALLING << channel) != 0;
+        let rising: bool = tcr & (TIM_TCR_EDGE_RISING << channel) != 0;
+        if (high && rising) || (!high && falling) {
+            let count: u32 = self.reg(TIM_TCNT);
+            self.set(common::tim_tcn(channel), count);
+            let flags: u32 = self.reg(TIM_FLG1);
+            self.set(TIM_FLG1, flags | mask);
+            self.update_plic();
+        }
+    }
+
+    // Levels of the output compare pins
+    pub fn timer_outputs(&self) -> u32 {
+        self.timer_outputs
+    }
+
+    // Moves TCNT straight to the tick before the next reset, overflow or
+    // compare match, then runs that tick in full
+    fn timer_advance(&mut self, mut ticks: u64) {
+        while ticks != 0 {
+            let count: u32 = self.reg(TIM_TCNT);
+            let mut until: u64 = u32::MAX as u64 - count as u64 + 1;
+            if self.reg(TIM_TSCR2) & TIM_TSCR2_TCRE_ENABLE != 0 {
+                let tc7: u32 = self.reg(common::tim_tcn(7));
+                if tc7 >= count {
+                    until = until.min(tc7 as u64 - count as u64 + 1);
+                }
+            }
+            let outputs: u32 = self.reg(TIM_IOS) & TIM_FLG1_MASK;
+            for channel in 0..8 {
+                let compare: u32 = self.reg(common::tim_tcn(channel));
+                if outputs & common::tim_ios_output(channel) != 0 && compare > count {
+                    until = until.min((compare - count) as u64);
+                }
+            }
+            let jump: u64 = ticks.min(until);
+            self.set(TIM_TCNT, count + (jump - 1) as u32);
+            self.timer_tick();
+            ticks -= jump;
+        }
+    }
+
+    fn timer_tick(&mut self) {
+        let count: u32 = self.reg(TIM_TCNT);
+        let tscr2: u32 = self.reg(TIM_TSCR2);
+        let next: u32 = if tscr2 & TIM_TSCR2_TCRE_ENABLE != 0 && count == self.reg(common::tim_tcn(7)) {
+            0
+        }
+        else if count == u32::MAX {
+            let flags: u32 = self.reg(TIM_FLG2);
+            self.set(TIM_FLG2, flags | TIM_FLG2_CLEAR);
+            self.timer_outputs ^= self.reg(TIM_TOV) & TIM_TOV_MASK;
+            self.reg(TIM_RLV)
+        }
+        else {
+            count + 1
+        };
+        self.set(TIM_TCNT, next);
+        let outputs: u32 = self.reg(TIM_IOS) & TIM_FLG1_MASK;
+        for channel in 0..8 {
+            let mask: u32 = common::tim_ios_output(channel);
+            if outputs & mask != 0 && self.reg(common::tim_tcn(channel)) == next {
+                let flags: u32 = self.reg(TIM_FLG1);
+                self.set(TIM_FLG1, flags | mask);
+                self.output_compare_action(channel);
+            }
+        }
+    }
+
+    fn output_compare_action(&mut self, channel: u32) {
+        let mask: u32 = common::tim_ios_output(channel);
+        match (self.reg(TIM_TCR) >> channel) & common::tim_tcr_output_mask(0) {
+            TIM_TCR_OUTPUT_TOGGLE => self.timer_outputs ^= mask,
+            TIM_TCR_OUTPUT_CLEAR =>  self.timer_outputs &= !mask,
+            TIM_TCR_OUTPUT_SET =>    self.timer_outputs |= mask,
+            _ =>                     (),
+        }
+    }
+
+    // PWM
+
+    // Level of a PWM channel output at the current cycle
+    pub fn pwm_output(&self, channel: u32) -> bool {
+        let offset: u32 = common::pwmn(channel);
+        let ctrl: u32 = self.reg(PWM_CONTROL + offset);
+        if ctrl & PWM_CONTROL_ENABLE == 0 {
+            return false;
+        }
+        let period: u32 = self.reg(PWM_PERIOD + offset);
+        let high: u32 = self.reg(PWM_DUTY + offset).saturating_sub(AFTX06_DUTY_OFFSET);
+        let counter: u32 = self.pwm_counters[channel as usize];
+        let active: bool = if ctrl & PWM_CONTROL_ALIGN_CENTER != 0 {
+            let start: u32 = period.saturating_sub(high) / 2;
+            counter >= start && counter < start + high
+        }
+        else {
+            counter < high
+        };
+        active != (ctrl & PWM_CONTROL_ACTIVE_LOW != 0)
+    }
+
+    // CLINT
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn machine_timer_pending(&self) -> bool {
+        let compare: u64 = ((self.reg(CLINT_MTIMECMP) as u64) << 32)
+            | (self.reg(CLINT_MTIMECMP + 0x04) as u64);
+        self.mtime >= compare
+    }
+
+    pub fn machine_software_pending(&self) -> bool {
+        self.reg(CLINT_MSIP) & CLINT_MSIP_MASK != 0
+    }
+
+    // PLIC
+
+    // Routes a peripheral's interrupt output to a PLIC source
+    pub fn connect_interrupt(&mut self, line: InterruptLine, source: InterruptSource) {
+        self.wiring[line as usize] = Some(source);
+        self.update_plic();
+    }
+
+    // Drives one of the interrupt lines not owned by a modelled peripheral
+    pub fn set_external_line(&mut self, source: InterruptSource, high: bool) {
+        if high { self.external_lines |= source.mask(); } else { self.external_lines &= !source.mask(); }
+        self.update_plic();
+    }
+
+    // Whether the PLIC is asserting the machine external interrupt
+    pub fn machine_external_pending(&self) -> bool {
+        self.highest_pending().is_some()
+    }
+
+    fn interrupt_lines(&self) -> u32 {
+        let mut lines: u32 = self.external_lines;
+        let asserted: [bool; 3] = [
+            self.reg(GPIO_INTERRUPT_STATUS) != 0,
+            self.reg(TIM_FLG1) & self.reg(TIM_TIE) & TIM_FLG1_MASK != 0,
+            self.reg(TIM_FLG2) & TIM_FLG2_CLEAR != 0 && self.reg(TIM_TSCR2) & TIM_TSCR2_TOI_ENABLE != 0,
+        ];
+        for (line, &high) in asserted.iter().enumerate() {
+            if let (true, Some(source)) = (high, self.wiring[line]) {
+                lines |= source.mask();
+            }
+        }
+        lines
+    }
+
+    // Level triggered gateways: a source pends again only once it is completed
+    fn update_plic(&mut self) {
+        let lines: u32 = self.interrupt_lines();
+        self.plic_pending |= lines & !self.plic_in_service;
+    }
+
+    fn highest_pending(&self) -> Option<InterruptSource> {
+        let candidates: u32 = self.plic_pending & self.reg(PLIC_IER);
+        let threshold: u32 = self.reg(PLIC_PTR);
+        let mut best: Option<(InterruptSource, u32)> = None;
+        for id in 1..=PLIC_SOURCES {
+            let source: InterruptSource = InterruptSource::from_id(id).unwrap();
+            let priority: u32 = self.reg(PLIC_IPR1 + 0x04 * (id - 1));
+            if candidates & source.mask() != 0 && priority > threshold
+                && best.map_or(true, |(_, p)| priority > p) {
+                best = Some((source, priority));
+            }
+        }
+        best.map(|(source, _)| source)
+    }
+
+    fn claim(&mut self) -> u32 {
+        match self.highest_pending() {
+            Some(source) => {
+                self.plic_pending &= !source.mask();
+                self.plic_in_service |= source.mask();
+                source.id()
+            }
+            None => PLIC_NO_INTERRUPT,
+        }
+    }
+}
+
+impl Backend for Simulator {
+    fn read(&mut self, address: u32) -> u32 {
+        let value: u32 = match address {
+            GPIO_DATA => {
+                let dir: u32 = self.reg(GPIO_DATA_DIRECTION);
+                (self.reg(GPIO_DATA) & dir) | (self.gpio_inputs & !dir)
+            }
+            CLINT_MTIME =>          (self.mtime >> 32) as u32,
+            a if a == CLINT_MTIME + 0x04 => self.mtime as u32,
+            PLIC_IPNDGR =>          self.plic_pending,
+            PLIC_CCRL =>            self.claim(),
+            _ =>                    self.reg(address),
+        };
+        if matches!(self.read_hook, Some((hooked, _)) if hooked == address) {
+            if let Some((_, hook)) = self.read_hook.take() {
+                hook(self);
+            }
+        }
+        if self.read_latency != 0 {
+            self.step(self.read_latency);
+        }
+        value
+    }
+
+    fn write(&mut self, address: u32, value: u32) {
+        match address {
+            GPIO_INTERRUPT_CLEAR => {
+                let status: u32 = self.reg(GPIO_INTERRUPT_STATUS);
+                self.set(GPIO_INTERRUPT_STATUS, status & !value);
+            }
+            GPIO_INTERRUPT_STATUS => (),
+            TIM_FLG1 | TIM_FLG2 => {
+                let flags: u32 = self.reg(address);
+                self.set(address, flags & !value);
+            }
+            TIM_TCF => {
+                for channel in 0..8 {
+                    if value & common::tim_ios_output(channel) != 0 {
+                        self.output_compare_action(channel);
+                    }
+                }
+            }
+            CLINT_MTIME => self.mtime = ((value as u64) << 32) | (self.mtime & 0xFFFF_FFFF),
+            a if a == CLINT_MTIME + 0x04 => self.mtime = (self.mtime & !0xFFFF_FFFF) | (value as u64),
+            PLIC_IPNDGR => (),
+            PLIC_CCRL => {
+                if let Some(source) = InterruptSource::from_id(value) {
+                    self.plic_in_service &= !source.mask();
+                }
+            }
+            _ => self.set(address, value),
+        }
+        self.update_plic();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ahb::clint::CLINT;
+    use crate::ahb::plic::PLIC;
+    use crate::apb::gpio::GPIO;
+    use crate::apb::pwm::{self, PWM};
+    use crate::apb::timer::{CaptureEdge, Channel, InterruptMode, OutputAction, TIM};
+    use crate::mock;
+
+    #[test]
+    fn timer_counts_with_prescaler() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_prescaler(Pre::DIV4);
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(40));
+        assert_eq!(tim.read_count(), 10);
+    }
+
+    #[test]
+    fn timer_output_compare_sets_flag_and_toggles() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_output_compare(Channel::CH2, OutputAction::Toggle, InterruptMode::Enabled, 5);
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(4));
+        assert_eq!(session.read(TIM_FLG1), 0);
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert_eq!(session.read(TIM_FLG1), 1 << 2);
+        assert_eq!(session.with(|sim: &mut Simulator| sim.timer_outputs()), 1 << 2);
+    }
+
+    #[test]
+    fn timer_overflow_reloads() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        session.write(TIM_TCNT, u32::MAX - 1);
+        session.write(TIM_RLV, 100);
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(2));
+        assert_eq!(tim.read_count(), 100);
+        assert_eq!(session.read(TIM_FLG2), TIM_FLG2_CLEAR);
+        session.write(TIM_FLG2, TIM_FLG2_CLEAR);
+        assert_eq!(session.read(TIM_FLG2), 0);
+    }
+
+    #[test]
+    fn timer_input_capture_latches_count() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_input_capture(Channel::CH1, CaptureEdge::Rising, InterruptMode::Enabled);
+        tim.enable();
+        session.with(|sim: &mut Simulator| {
+            sim.step(7);
+            sim.set_timer_input(1, true);
+        });
+        assert_eq!(tim.read_input_capture(Channel::CH1), 7);
+        assert_eq!(session.read(TIM_FLG1), 1 << 1);
+    }
+
+    #[test]
+    fn gpio_edges_raise_plic_interrupt() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut gpio = GPIO::new();
+        let mut plic = PLIC::new();
+        let source: InterruptSource = InterruptSource::Raw(6);
+        session.with(|sim: &mut Simulator| sim.connect_interrupt(InterruptLine::Gpio, source));
+        plic.set_priority(source, 1).unwrap();
+        plic.enable_interrupt(source).unwrap();
+        gpio.enable_interrupt_negedge(Pin::PIN4);
+        session.with(|sim: &mut Simulator| sim.set_gpio_input(4, true));
+        assert_eq!(gpio.interrupt_status(Pin::PIN4), 0);
+        session.with(|sim: &mut Simulator| sim.set_gpio_input(4, false));
+        assert_eq!(gpio.interrupt_status(Pin::PIN4), Pin::PIN4 as u32);
+        assert!(session.with(|sim: &mut Simulator| sim.machine_external_pending()));
+        assert_eq!(plic.claim(), Some(source));
+        gpio.clear_interrupt(Pin::PIN4);
+        plic.complete(source).unwrap();
+        assert_eq!(plic.claim(), None);
+    }
+
+    #[test]
+    fn unconnected_lines_stay_quiet() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut gpio = GPIO::new();
+        let mut plic = PLIC::new();
+        plic.enable_interrupts(u32::MAX);
+        for id in 1..=PLIC_SOURCES {
+            plic.set_priority(InterruptSource::Raw(id), 1).unwrap();
+        }
+        gpio.enable_interrupt_posedge(Pin::PIN0);
+        session.with(|sim: &mut Simulator| sim.set_gpio_input(0, true));
+        assert_eq!(gpio.interrupt_status(Pin::PIN0), Pin::PIN0 as u32);
+        assert_eq!(plic.claim(), None);
+    }
+
+    #[test]
+    fn plic_respects_priority_and_threshold() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut plic = PLIC::new();
+        plic.set_priority(InterruptSource::Raw(5), 2).unwrap();
+        plic.set_priority(InterruptSource::Raw(9), 5).unwrap();
+        plic.enable_interrupts(InterruptSource::Raw(5).mask() | InterruptSource::Raw(9).mask());
+        plic.set_threshold(2);
+        session.with(|sim: &mut Simulator| {
+            sim.set_external_line(InterruptSource::Raw(5), true);
+            sim.set_external_line(InterruptSource::Raw(9), true);
+        });
+        assert_eq!(plic.claim(), Some(InterruptSource::Raw(9)));
+        assert_eq!(plic.claim(), None);
+        plic.set_threshold(0);
+        assert_eq!(plic.claim(), Some(InterruptSource::Raw(5)));
+    }
+
+    #[test]
+    fn plic_ignores_completion_of_invalid_ids() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut plic = PLIC::new();
+        plic.set_priority(InterruptSource::Raw(1), 1).unwrap();
+        plic.enable_interrupt(InterruptSource::Raw(1)).unwrap();
+        session.with(|sim: &mut Simulator| sim.set_external_line(InterruptSource::Raw(1), true));
+        assert_eq!(plic.claim(), Some(InterruptSource::Raw(1)));
+        // Neither ID names source 1, so it stays in service and cannot pend again
+        session.write(PLIC_CCRL, 0);
+        session.write(PLIC_CCRL, PLIC_SOURCES + 1);
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert_eq!(plic.claim(), None);
+        plic.complete(InterruptSource::Raw(1)).unwrap();
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert_eq!(plic.claim(), Some(InterruptSource::Raw(1)));
+    }
+
+    #[test]
+    fn timer_steps_over_long_runs() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_output_compare(Channel::CH0, OutputAction::Toggle, InterruptMode::Disabled, 1_000);
+        tim.enable();
+        // Two compare matches and one overflow in 5e9 ticks
+        session.with(|sim: &mut Simulator| sim.step(5_000_000_000));
+        assert_eq!(tim.read_count(), (5_000_000_000u64 - (1 << 32)) as u32);
+        assert!(tim.overflow_flag());
+        assert!(tim.interrupt_flag(Channel::CH0));
+        assert_eq!(session.with(|sim: &mut Simulator| sim.timer_outputs()), 0);
+        tim.clear_interrupt(Channel::CH0);
+        session.with(|sim: &mut Simulator| sim.step((1 << 32) - 705_032_704 + 999));
+        assert_eq!(session.with(|sim: &mut Simulator| sim.timer_outputs()), 0);
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert_eq!(session.with(|sim: &mut Simulator| sim.timer_outputs()), 1);
+    }
+
+    #[test]
+    fn timer_steps_through_counter_resets() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut tim = TIM::new();
+        tim.set_prescaler(Pre::DIV2);
+        tim.set_output_compare(Channel::CH7, OutputAction::Disconnect, InterruptMode::Disabled, 99);
+        tim.enable_counter_reset();
+        tim.enable();
+        session.with(|sim: &mut Simulator| sim.step(3));
+        assert_eq!(tim.read_count(), 1);
+        session.with(|sim: &mut Simulator| sim.step(20_000_101));
+        assert_eq!(tim.read_count(), 52);
+        assert!(!tim.overflow_flag());
+    }
+
+    #[test]
+    fn clint_machine_timer() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut clint = CLINT::new();
+        clint.arm_timer(1000);
+        session.with(|sim: &mut Simulator| sim.step(999));
+        assert!(!session.with(|sim: &mut Simulator| sim.machine_timer_pending()));
+        session.with(|sim: &mut Simulator| sim.step(1));
+        assert!(session.with(|sim: &mut Simulator| sim.machine_timer_pending()));
+        assert_eq!(clint.now(), 1000);
+    }
+
+    #[test]
+    fn pwm_output_follows_duty() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut pwm = PWM::new();
+        pwm.set_period(pwm::Channel::CH0, 10).unwrap();
+        pwm.set_duty(pwm::Channel::CH0, 3).unwrap();
+        pwm.enable(pwm::Channel::CH0);
+        let levels: Vec<bool> = (0..10).map(|_| session.with(|sim: &mut Simulator| {
+            let level: bool = sim.pwm_output(0);
+            sim.step(1);
+            level
+        })).collect();
+        assert_eq!(levels.iter().filter(|&&level| level).count(), 3);
+        assert!(levels[0] && !levels[3]);
+    }
+
+    #[cfg(feature = "pwm-2ch")]
+    #[test]
+    fn pwm_channels_run_independently() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut pwm = PWM::new();
+        pwm.set_period(pwm::Channel::CH0, 10).unwrap();
+        pwm.set_duty(pwm::Channel::CH0, 3).unwrap();
+        pwm.set_period(pwm::Channel::CH1, 20).unwrap();
+        pwm.set_duty(pwm::Channel::CH1, 15).unwrap();
+        pwm.enable(pwm::Channel::CH0);
+        pwm.enable(pwm::Channel::CH1);
+        let mut high: [usize; 2] = [0; 2];
+        for _ in 0..20 {
+            session.with(|sim: &mut Simulator| {
+                high[0] += sim.pwm_output(0) as usize;
+                high[1] += sim.pwm_output(1) as usize;
+                sim.step(1);
+            });
+        }
+        assert_eq!(high, [6, 15]);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::ahb::clint::CLINT;
+use crate::ahb::plic::PLIC;
+use crate::apb::gpio::GPIO;
+use crate::apb::pwm::PWM;
+use crate::apb::timer::TIM;
+use crate::common::Error;
+
+// Ownership Flags
+pub const GPIO_TAKEN: u32 =  1 << 0;
+pub const PWM_TAKEN: u32 =   1 << 1;
+pub const TIM_TAKEN: u32 =   1 << 2;
+pub const CLINT_TAKEN: u32 = 1 << 3;
+pub const PLIC_TAKEN: u32 =  1 << 4;
+pub const ALL_TAKEN: u32 =   GPIO_TAKEN | PWM_TAKEN | TIM_TAKEN | CLINT_TAKEN | PLIC_TAKEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AlreadyTaken;
+
+// A peripheral that is already owned is busy, so try_new()? works in code returning Error
+impl From<AlreadyTaken> for Error {
+    fn from(_: AlreadyTaken) -> Error {
+        Error::Busy
+    }
+}
+
+// Only accessed inside a critical section
+static mut TAKEN: u32 = 0;
+
+// Marks every peripheral in mask as taken, unless any of them already is
+pub(crate) fn claim(mask: u32) -> bool {
+    critical_section::with(|_| unsafe {
+        if TAKEN & mask != 0 {
+            false
+        }
+        else {
+            TAKEN |= mask;
+            true
+        }
+    })
+}
+
+pub(crate) fn release(mask: u32) {
+    critical_section::with(|_| unsafe {
+        TAKEN &= !mask;
+    })
+}
+
+pub fn taken(mask: u32) -> bool {
+    critical_section::with(|_| unsafe { TAKEN & mask != 0 })
+}
+
+#[allow(non_snake_case)]
+pub struct Peripherals {
+    pub GPIO:  GPIO,
+    pub PWM:   PWM,
+    pub TIM:   TIM,
+    pub CLINT: CLINT,
+    pub PLIC:  PLIC,
+}
+
+impl Peripherals {
+    // Returns all peripherals once; None if any of them is already owned
+    pub fn take() -> Option<Peripherals> {
+        if claim(ALL_TAKEN) {
+            Some(unsafe { Peripherals::conjure() })
+        }
+        else {
+            None
+        }
+    }
+
+    /// # Safety
+    /// Returns every peripheral regardless of who owns them and leaves the
+    /// ownership flags as they are. The caller must not race the owning
+    /// instances, including any taken later through take() or try_new().
+    pub unsafe fn steal() -> Peripherals {
+        Peripherals::conjure()
+    }
+
+    // Resets every peripheral and releases it for Peripherals::take or try_new
+    pub fn free(self) {
+        self.GPIO.free();
+        self.PWM.free();
+        self.TIM.free();
+        self.CLINT.free();
+        self.PLIC.free();
+    }
+
+    unsafe fn conjure() -> Peripherals {
+        Peripherals {
+            GPIO:  GPIO::steal(),
+            PWM:   PWM::steal(),
+            TIM:   TIM::steal(),
+            CLINT: CLINT::steal(),
+            PLIC:  PLIC::steal(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn take_once() {
+        let _session = mock::session();
+        assert!(Peripherals::take().is_some());
+        assert!(Peripherals::take().is_none());
+        assert!(taken(ALL_TAKEN));
+    }
+
+    #[test]
+    fn take_fails_after_new() {
+        let _session = mock::session();
+        let _gpio = GPIO::new();
+        assert!(Peripherals::take().is_none());
+        assert!(!taken(TIM_TAKEN));
+    }
+
+    #[test]
+    fn free_allows_take_again() {
+        let session = mock::session();
+        let mut p = Peripherals::take().unwrap();
+        p.TIM.enable();
+        assert!(TIM::try_new().is_err());
+        p.free();
+        assert_eq!(session.read(crate::apb::timer::TIM_TSCR), 0);
+        let tim = TIM::try_new().unwrap();
+        assert!(Peripherals::take().is_none());
+        tim.free();
+        assert!(Peripherals::take().is_some());
+    }
+
+    #[test]
+    fn taken_peripherals_are_busy() {
+        let _session = mock::session();
+        fn claim_timer() -> Result<TIM, Error> {
+            Ok(TIM::try_new()?)
+        }
+        let _tim = claim_timer().unwrap();
+        assert_eq!(claim_timer().err(), Some(Error::Busy));
+    }
+
+    #[test]
+    fn steal_leaves_ownership_alone() {
+        let _session = mock::session();
+        let _tim = TIM::new();
+        let _p = unsafe { Peripherals::steal() };
+        assert!(taken(TIM_TAKEN));
+        assert!(!taken(GPIO_TAKEN));
+        assert!(GPIO::try_new().is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_fails_after_take() {
+        let _session = mock::session();
+        let _p = Peripherals::take();
+        let _tim = TIM::new();
+    }
+}
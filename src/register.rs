@@ -0,0 +1,68 @@
+// Register access shared by every driver. On hardware this is plain volatile
+// MMIO; with the mock feature every access is routed to mock::Backend instead.
+
+#[repr(transparent)]
+pub struct RW<T: Copy> {
+    // Only its address is used under the mock
+    #[cfg_attr(any(test, feature = "mock"), allow(dead_code))]
+    register: volatile_register::RW<T>
+}
+
+#[cfg(not(any(test, feature = "mock")))]
+impl RW<u32> {
+    pub fn read(&self) -> u32 {
+        self.register.read()
+    }
+
+    pub unsafe fn write(&self, value: u32) {
+        self.register.write(value)
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+impl RW<u32> {
+    pub fn read(&self) -> u32 {
+        crate::mock::read(crate::mock::physical_address(self as *const RW<u32> as usize))
+    }
+
+    pub unsafe fn write(&self, value: u32) {
+        crate::mock::write(crate::mock::physical_address(self as *const RW<u32> as usize), value)
+    }
+}
+
+// Pointer to a peripheral's register block. Registers are only accessed with
+// volatile reads and writes through &self, so copies never create a &mut.
+pub struct Block<T> {
+    ptr: *const T
+}
+
+// Owning the driver that holds the pointer is what grants access to the
+// registers, so the driver may move to another context such as an interrupt
+unsafe impl<T> Send for Block<T> {}
+
+impl<T> Clone for Block<T> {
+    fn clone(&self) -> Block<T> {
+        *self
+    }
+}
+
+impl<T> Copy for Block<T> {}
+
+impl<T> core::ops::Deref for Block<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+// Returns the register block of the peripheral at address
+#[cfg(not(any(test, feature = "mock")))]
+pub unsafe fn block<T>(address: u32) -> Block<T> {
+    Block { ptr: address as usize as *const T }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub unsafe fn block<T>(address: u32) -> Block<T> {
+    Block { ptr: crate::mock::host_address(address) as *const T }
+}
@@ -0,0 +1,322 @@
+use crate::apb::pwm::{PWMChannel, PwmConfig};
+use crate::apb::waveform::{OutputRef, Waveform};
+use crate::common::Error;
+use crate::time::{Duration, Hertz};
+use embedded_hal::pwm::SetDutyCycle;
+
+// Servo Constants
+pub const SERVO_FREQUENCY: u32 =        50;
+pub const SERVO_PERIOD_US: u32 =        1_000_000 / SERVO_FREQUENCY;
+pub const SERVO_MIN_PULSE_US: u32 =     1_000;
+pub const SERVO_MAX_PULSE_US: u32 =     2_000;
+pub const SERVO_RANGE_DEGREES: u32 =    180;
+
+// Maps angles and pulse widths onto any 50 Hz output with a duty cycle.
+// Pulses are set in microseconds; the trim is added after calibration so a
+// servo whose centre is off can be corrected without changing its range.
+pub struct Servo<P: SetDutyCycle> {
+    output: P,
+    min_pulse_us: u32,
+    max_pulse_us: u32,
+    range_degrees: u32,
+    trim_us: i32,
+    pulse_us: Option<u32>,
+}
+
+impl Servo<PWMChannel> {
+    // Sets the PWM channel to 50 Hz and enables it with the output low
+    pub fn on_pwm(mut ch: PWMChannel) -> Result<Servo<PWMChannel>, Error> {
+        let config: PwmConfig = PwmConfig::new().frequency(SERVO_FREQUENCY).duty(0).enabled(true);
+        ch.configure(&config)?;
+        Ok(Servo::new(ch))
+    }
+}
+
+impl<'a> Servo<OutputRef<'a>> {
+    // Pulses come from the waveform engine, so Waveform::poll() has to keep running
+    pub fn on_waveform(waveform: &'a Waveform) -> Result<Servo<OutputRef<'a>>, Error> {
+        let output: OutputRef<'a> = waveform.output(Hertz(SERVO_FREQUENCY))?;
+        Ok(Servo::new(output))
+    }
+}
+
+// Errors from the output are passed through, so it must use this crate's Error
+impl<P: SetDutyCycle<Error = Error>> Servo<P> {
+    // The output must already run at SERVO_FREQUENCY
+    pub fn new(output: P) -> Servo<P> {
+        Servo {
+            output,
+            min_pulse_us: SERVO_MIN_PULSE_US,
+            max_pulse_us: SERVO_MAX_PULSE_US,
+            range_degrees: SERVO_RANGE_DEGREES,
+            trim_us: 0,
+            pulse_us: None,
+        }
+    }
+
+    // Pulse widths at 0 degrees and at the end of the range
+    pub fn set_pulse_range(&mut self, min_us: u32, max_us: u32) -> Result<(), Error> {
+        if min_us >= max_us || max_us > SERVO_PERIOD_US {
+            return Err(Error::DutyOutOfRange);
+        }
+        self.min_pulse_us = min_us;
+        self.max_pulse_us = max_us;
+        Ok(())
+    }
+
+    pub fn get_pulse_range(&self) -> (u32, u32) {
+        (self.min_pulse_us, self.max_pulse_us)
+    }
+
+    pub fn set_range_degrees(&mut self, degrees: u32) -> Result<(), Error> {
+        if degrees == 0 {
+            return Err(Error::UnsupportedMode);
+        }
+        self.range_degrees = degrees;
+        Ok(())
+    }
+
+    // Moves a servo that is holding a position to the new trim right away
+    pub fn set_trim(&mut self, trim_us: i32) -> Result<(), Error> {
+        let previous: i32 = self.trim_us;
+        self.trim_us = trim_us;
+        if let Some(pulse_us) = self.pulse_us {
+            if let Err(error) = self.set_pulse_us(pulse_us) {
+                self.trim_us = previous;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_trim(&self) -> i32 {
+        self.trim_us
+    }
+
+    pub fn set_angle(&mut self, degrees: u32) -> Result<(), Error> {
+        if degrees > self.range_degrees {
+            return Err(Error::DutyOutOfRange);
+        }
+        let span: u64 = (self.max_pulse_us - self.min_pulse_us) as u64;
+        let offset: u64 = (span * degrees as u64 + self.range_degrees as u64 / 2) / self.range_degrees as u64;
+        self.set_pulse_us(self.min_pulse_us + offset as u32)
+    }
+
+    pub fn center(&mut self) -> Result<(), Error> {
+        self.set_pulse_us((self.min_pulse_us + self.max_pulse_us) / 2)
+    }
+
+    // Pulse width before trim, which has to lie within the calibrated range
+    pub fn set_pulse_us(&mut self, pulse_us: u32) -> Result<(), Error> {
+        if pulse_us < self.min_pulse_us || self.max_pulse_us < pulse_us {
+            return Err(Error::DutyOutOfRange);
+        }
+        let trimmed: u32 = (pulse_us as i64 + self.trim_us as i64).clamp(0, SERVO_PERIOD_US as i64) as u32;
+        let max: u64 = self.output.max_duty_cycle() as u64;
+        let duty: u64 = (trimmed as u64 * max + SERVO_PERIOD_US as u64 / 2) / SERVO_PERIOD_US as u64;
+        self.output.set_duty_cycle(duty as u16)?;
+        self.pulse_us = Some(pulse_us);
+        Ok(())
+    }
+
+    pub fn set_pulse(&mut self, pulse: Duration) -> Result<(), Error> {
+        self.set_pulse_us(pulse.as_micros().min(u32::MAX as u64) as u32)
+    }
+
+    // Last pulse width commanded, before trim
+    pub fn get_pulse_us(&self) -> Option<u32> {
+        self.pulse_us
+    }
+
+    // Stops the pulses so the servo no longer holds its position
+    pub fn release(&mut self) -> Result<(), Error> {
+        self.output.set_duty_cycle_fully_off()?;
+        self.pulse_us = None;
+        Ok(())
+    }
+
+    pub fn free(self) -> P {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apb::pwm::{PWM, PWM_DUTY, PWM_PERIOD, AFTX06_DUTY_OFFSET};
+    use crate::apb::timer::{Pre, TIM};
+    use crate::common;
+    use crate::mock::{self, sim::Simulator};
+
+    // Pulse width driven by PWM channel 0, in microseconds
+    fn pwm_pulse_us(session: &mock::Session) -> f64 {
+        let cycles: u32 = session.read(PWM_DUTY) - AFTX06_DUTY_OFFSET;
+        cycles as f64 * 1_000_000.0 / common::CHIP_FREQ as f64
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.5, "{} is not close to {}", actual, expected);
+    }
+
+    #[test]
+    fn pwm_servo_runs_at_50_hz() {
+        let session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        assert_eq!(session.read(PWM_PERIOD), common::CHIP_FREQ / SERVO_FREQUENCY);
+        assert_eq!(servo.get_pulse_us(), None);
+        servo.center().unwrap();
+        assert_near(pwm_pulse_us(&session), 1_500.0);
+        assert_eq!(servo.get_pulse_us(), Some(1_500));
+    }
+
+    #[test]
+    fn angles_map_across_calibrated_range() {
+        let session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        servo.set_angle(0).unwrap();
+        assert_near(pwm_pulse_us(&session), 1_000.0);
+        servo.set_angle(45).unwrap();
+        assert_near(pwm_pulse_us(&session), 1_250.0);
+        servo.set_angle(180).unwrap();
+        assert_near(pwm_pulse_us(&session), 2_000.0);
+        servo.set_pulse_range(500, 2_500).unwrap();
+        servo.set_range_degrees(270).unwrap();
+        servo.set_angle(135).unwrap();
+        assert_near(pwm_pulse_us(&session), 1_500.0);
+        assert_eq!(servo.set_angle(271), Err(Error::DutyOutOfRange));
+    }
+
+    #[test]
+    fn trim_shifts_every_pulse() {
+        let session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        servo.set_trim(-40).unwrap();
+        servo.center().unwrap();
+        assert_near(pwm_pulse_us(&session), 1_460.0);
+        assert_eq!(servo.get_pulse_us(), Some(1_500));
+        servo.set_trim(25).unwrap();
+        servo.set_pulse(Duration::from_micros(2_000)).unwrap();
+        assert_near(pwm_pulse_us(&session), 2_025.0);
+    }
+
+    #[test]
+    fn trim_moves_a_held_position() {
+        let session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        servo.set_angle(90).unwrap();
+        servo.set_trim(30).unwrap();
+        assert_near(pwm_pulse_us(&session), 1_530.0);
+        assert_eq!(servo.get_trim(), 30);
+        servo.release().unwrap();
+        servo.set_trim(-30).unwrap();
+        assert_eq!(session.read(PWM_DUTY), AFTX06_DUTY_OFFSET);
+    }
+
+    // Output that refuses every duty with the given error
+    struct Failing(Error);
+
+    impl embedded_hal::pwm::ErrorType for Failing {
+        type Error = Error;
+    }
+
+    impl SetDutyCycle for Failing {
+        fn max_duty_cycle(&self) -> u16 {
+            1_000
+        }
+
+        fn set_duty_cycle(&mut self, _duty: u16) -> Result<(), Error> {
+            Err(self.0)
+        }
+    }
+
+    #[test]
+    fn output_errors_pass_through() {
+        let mut servo = Servo::new(Failing(Error::UnsupportedMode));
+        assert_eq!(servo.center(), Err(Error::UnsupportedMode));
+        assert_eq!(servo.release(), Err(Error::UnsupportedMode));
+        assert_eq!(servo.get_pulse_us(), None);
+    }
+
+    #[test]
+    fn out_of_range_calibration_and_pulses() {
+        let _session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        assert_eq!(servo.set_pulse_range(2_000, 1_000), Err(Error::DutyOutOfRange));
+        assert_eq!(servo.set_pulse_range(1_000, SERVO_PERIOD_US + 1), Err(Error::DutyOutOfRange));
+        assert_eq!(servo.set_range_degrees(0), Err(Error::UnsupportedMode));
+        assert_eq!(servo.get_pulse_range(), (SERVO_MIN_PULSE_US, SERVO_MAX_PULSE_US));
+        assert_eq!(servo.set_pulse_us(999), Err(Error::DutyOutOfRange));
+        assert_eq!(servo.set_pulse_us(2_001), Err(Error::DutyOutOfRange));
+    }
+
+    #[test]
+    fn release_stops_pulses() {
+        let session = mock::session();
+        let mut servo = Servo::on_pwm(PWM::new().split().ch0).unwrap();
+        servo.center().unwrap();
+        servo.release().unwrap();
+        assert_eq!(session.read(PWM_DUTY), AFTX06_DUTY_OFFSET);
+        assert_eq!(servo.get_pulse_us(), None);
+    }
+
+    #[cfg(feature = "pwm-2ch")]
+    #[test]
+    fn servos_on_separate_pwm_channels() {
+        let session = mock::session();
+        let parts = PWM::new().split();
+        let mut pan = Servo::on_pwm(parts.ch0).unwrap();
+        let mut tilt = Servo::on_pwm(parts.ch1).unwrap();
+        pan.set_angle(0).unwrap();
+        tilt.set_angle(180).unwrap();
+        assert_near(pwm_pulse_us(&session), 1_000.0);
+        let cycles: u32 = session.read(PWM_DUTY + common::pwmn(1)) - AFTX06_DUTY_OFFSET;
+        assert_near(cycles as f64 * 1_000_000.0 / common::CHIP_FREQ as f64, 2_000.0);
+    }
+
+    // Measures the high time of a TIM output over one servo period, polling
+    // the waveforms every microsecond like the TIM interrupt would
+    fn waveform_pulses_us(session: &mock::Session, waveforms: &[&Waveform]) -> Vec<u64> {
+        let step: u64 = common::CHIP_FREQ as u64 / 1_000_000;
+        let mut high: Vec<u64> = vec![0; waveforms.len()];
+        for _ in 0..SERVO_PERIOD_US {
+            session.with(|sim: &mut Simulator| sim.step(step));
+            let outputs: u32 = session.with(|sim: &mut Simulator| sim.timer_outputs());
+            for (n, waveform) in waveforms.iter().enumerate() {
+                waveform.poll();
+                high[n] += (outputs & common::timn(waveform.channel().channel() as u32) != 0) as u64;
+            }
+        }
+        high
+    }
+
+    #[test]
+    fn waveform_servos_run_while_polled() {
+        let session = mock::session();
+        session.install(Simulator::new());
+        let mut parts = TIM::new().split();
+        parts.counter.set_prescaler(Pre::DIV128);
+        parts.counter.start_free_running();
+        let left = Waveform::new(parts.ch2);
+        let right = Waveform::new(parts.ch5);
+        let mut left_servo = Servo::on_waveform(&left).unwrap();
+        let mut right_servo = Servo::on_waveform(&right).unwrap();
+        left_servo.set_angle(0).unwrap();
+        right_servo.set_angle(180).unwrap();
+        // Skip the first period, which starts part way through a pulse
+        waveform_pulses_us(&session, &[&left, &right]);
+        let high = waveform_pulses_us(&session, &[&left, &right]);
+        assert!((990..=1_010).contains(&high[0]), "{:?}", high);
+        assert!((1_990..=2_010).contains(&high[1]), "{:?}", high);
+        // The servos still move while poll() keeps running
+        left_servo.set_angle(90).unwrap();
+        waveform_pulses_us(&session, &[&left, &right]);
+        let high = waveform_pulses_us(&session, &[&left, &right]);
+        assert!((1_490..=1_510).contains(&high[0]), "{:?}", high);
+        left_servo.release().unwrap();
+        let high = waveform_pulses_us(&session, &[&left, &right]);
+        assert_eq!(high[0], 0);
+        assert!(right.is_active());
+        right_servo.free();
+        assert!(right.is_active());
+    }
+}
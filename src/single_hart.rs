@@ -0,0 +1,27 @@
+use core::arch::asm;
+use critical_section::RawRestoreState;
+
+// Machine interrupt enable bit in mstatus, also written as the 8 in the CSR immediates
+const MSTATUS_MIE: usize = 1 << 3;
+
+// The AFTx06 has a single hart, so masking its machine interrupts is enough to
+// make a critical section exclusive. Release restores the MIE bit seen by the
+// matching acquire, so nested sections only re-enable interrupts at the end of
+// the outermost one.
+struct SingleHart;
+
+critical_section::set_impl!(SingleHart);
+
+unsafe impl critical_section::Impl for SingleHart {
+    unsafe fn acquire() -> RawRestoreState {
+        let mstatus: usize;
+        asm!("csrrci {}, mstatus, 8", out(reg) mstatus);
+        mstatus & MSTATUS_MIE != 0
+    }
+
+    unsafe fn release(was_enabled: RawRestoreState) {
+        if was_enabled {
+            asm!("csrsi mstatus, 8");
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use crate::ahb::clint::{CLINT, MTime};
+use crate::common::{self, CHIP_FREQ};
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
+// Time Constants
+pub const TICKS_PER_SECOND: u64 = CHIP_FREQ as u64;
+pub const MICROS_PER_SECOND: u64 = 1_000_000;
+pub const MILLIS_PER_SECOND: u64 = 1_000;
+
+// Computes value * num / den without overflowing the intermediate product
+fn scale(value: u64, num: u64, den: u64) -> Option<u64> {
+    let whole: u64 = (value / den).checked_mul(num)?;
+    let part: u64 = (value % den).checked_mul(num)? / den;
+    whole.checked_add(part)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Hertz(pub u32);
+
+impl Hertz {
+    // Length of one cycle, rounded to the nearest tick
+    pub fn period(&self) -> Duration {
+        if self.0 == 0 { return Duration::MAX; }
+        Duration::from_ticks(common::rounding_division(CHIP_FREQ, self.0) as u64)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Duration {
+    ticks: u64
+}
+
+impl Duration {
+    pub const ZERO: Duration = Duration { ticks: 0 };
+    pub const MAX: Duration =  Duration { ticks: u64::MAX };
+
+    pub const fn from_ticks(ticks: u64) -> Duration {
+        Duration { ticks }
+    }
+
+    pub fn from_micros(micros: u64) -> Duration {
+        Duration { ticks: scale(micros, TICKS_PER_SECOND, MICROS_PER_SECOND).unwrap_or(u64::MAX) }
+    }
+
+    pub fn from_millis(millis: u64) -> Duration {
+        Duration { ticks: scale(millis, TICKS_PER_SECOND, MILLIS_PER_SECOND).unwrap_or(u64::MAX) }
+    }
+
+    pub fn from_secs(secs: u64) -> Duration {
+        Duration { ticks: secs.saturating_mul(TICKS_PER_SECOND) }
+    }
+
+    pub fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        scale(self.ticks, MICROS_PER_SECOND, TICKS_PER_SECOND).unwrap_or(u64::MAX)
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        scale(self.ticks, MILLIS_PER_SECOND, TICKS_PER_SECOND).unwrap_or(u64::MAX)
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.ticks / TICKS_PER_SECOND
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.ticks == 0
+    }
+
+    pub fn checked_add(self, rhs: Duration) -> Option<Duration> {
+        self.ticks.checked_add(rhs.ticks).map(Duration::from_ticks)
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Option<Duration> {
+        self.ticks.checked_sub(rhs.ticks).map(Duration::from_ticks)
+    }
+
+    pub fn checked_mul(self, rhs: u32) -> Option<Duration> {
+        self.ticks.checked_mul(rhs as u64).map(Duration::from_ticks)
+    }
+
+    pub fn checked_div(self, rhs: u32) -> Option<Duration> {
+        self.ticks.checked_div(rhs as u64).map(Duration::from_ticks)
+    }
+
+    pub fn saturating_add(self, rhs: Duration) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_add(rhs.ticks))
+    }
+
+    pub fn saturating_sub(self, rhs: Duration) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_sub(rhs.ticks))
+    }
+
+    pub fn saturating_mul(self, rhs: u32) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_mul(rhs as u64))
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        self.checked_add(rhs).expect("Overflow when adding durations.")
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        self.checked_sub(rhs).expect("Overflow when subtracting durations.")
+    }
+}
+
+impl SubAssign for Duration {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+// A point in time measured in mtime ticks since reset
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Instant {
+    ticks: u64
+}
+
+impl Instant {
+    pub const fn from_ticks(ticks: u64) -> Instant {
+        Instant { ticks }
+    }
+
+    pub fn as_ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.ticks.checked_sub(earlier.ticks).map(Duration::from_ticks)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_ticks(self.ticks.saturating_sub(earlier.ticks))
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.ticks.checked_add(duration.ticks).map(Instant::from_ticks)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.ticks.checked_sub(duration.ticks).map(Instant::from_ticks)
+    }
+
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        Instant::from_ticks(self.ticks.saturating_add(duration.ticks))
+    }
+
+    pub fn saturating_sub(&self, duration: Duration) -> Instant {
+        Instant::from_ticks(self.ticks.saturating_sub(duration.ticks))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).expect("Overflow when adding duration to instant.")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs).expect("Overflow when subtracting duration from instant.")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.checked_duration_since(rhs).expect("Supplied instant is later than self.")
+    }
+}
+
+// Instants from mtime. Only reads the timer, so the CLINT stays with its owner.
+#[derive(Clone, Copy)]
+pub struct Clock {
+    mtime: MTime
+}
+
+impl Clock {
+    pub fn new(clint: &CLINT) -> Clock {
+        Clock { mtime: clint.mtime() }
+    }
+
+    pub fn now(&self) -> Instant {
+        Instant::from_ticks(self.mtime.now())
+    }
+
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    pub fn has_elapsed(&self, since: Instant, duration: Duration) -> bool {
+        self.elapsed(since) >= duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ahb::clint::CLINT_MTIME;
+    use crate::mock;
+
+    #[test]
+    fn scale_avoids_intermediate_overflow() {
+        assert_eq!(scale(10, 3, 4), Some(7));
+        assert_eq!(scale(u64::MAX, 1, 2), Some(u64::MAX / 2));
+        assert_eq!(scale(u64::MAX / 10, 10, 100), Some(u64::MAX / 100));
+        assert_eq!(scale(u64::MAX, 2, 1), None);
+    }
+
+    #[test]
+    fn duration_conversions() {
+        assert_eq!(Duration::from_micros(1).as_ticks(), 100);
+        assert_eq!(Duration::from_millis(1).as_ticks(), 100_000);
+        assert_eq!(Duration::from_secs(2).as_ticks(), 200_000_000);
+        assert_eq!(Duration::from_ticks(150).as_micros(), 1);
+        assert_eq!(Duration::from_ticks(250_000).as_millis(), 2);
+        assert_eq!(Duration::from_ticks(250_000_000).as_secs(), 2);
+        assert_eq!(Duration::from_millis(7).as_micros(), 7_000);
+        assert_eq!(Duration::MAX.as_micros(), u64::MAX / 100);
+    }
+
+    #[test]
+    fn duration_conversions_saturate() {
+        assert_eq!(Duration::from_micros(u64::MAX), Duration::MAX);
+        assert_eq!(Duration::from_millis(u64::MAX), Duration::MAX);
+        assert_eq!(Duration::from_secs(u64::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn duration_arithmetic() {
+        let one: Duration = Duration::from_ticks(1);
+        assert_eq!(Duration::MAX.checked_add(one), None);
+        assert_eq!(Duration::ZERO.checked_sub(one), None);
+        assert_eq!(one.checked_mul(3), Some(Duration::from_ticks(3)));
+        assert_eq!(Duration::MAX.checked_mul(2), None);
+        assert_eq!(Duration::from_ticks(9).checked_div(2), Some(Duration::from_ticks(4)));
+        assert_eq!(one.checked_div(0), None);
+        assert_eq!(Duration::MAX.saturating_add(one), Duration::MAX);
+        assert_eq!(Duration::ZERO.saturating_sub(one), Duration::ZERO);
+        assert_eq!(Duration::MAX.saturating_mul(2), Duration::MAX);
+        let mut total: Duration = one + one;
+        total -= one;
+        assert_eq!(total, one);
+        assert!(Duration::ZERO.is_zero());
+    }
+
+    #[test]
+    #[should_panic]
+    fn duration_subtraction_panics_on_underflow() {
+        let _ = Duration::ZERO - Duration::from_ticks(1);
+    }
+
+    #[test]
+    fn instant_arithmetic() {
+        let earlier: Instant = Instant::from_ticks(100);
+        let later: Instant = earlier + Duration::from_ticks(50);
+        assert_eq!(later.as_ticks(), 150);
+        assert_eq!(later - earlier, Duration::from_ticks(50));
+        assert_eq!(earlier.checked_duration_since(later), None);
+        assert_eq!(earlier.saturating_duration_since(later), Duration::ZERO);
+        assert_eq!(earlier.checked_sub(Duration::from_ticks(101)), None);
+        assert_eq!(earlier.saturating_sub(Duration::from_ticks(101)), Instant::from_ticks(0));
+        assert_eq!(later.saturating_add(Duration::MAX), Instant::from_ticks(u64::MAX));
+    }
+
+    #[test]
+    #[should_panic]
+    fn instant_difference_panics_when_reversed() {
+        let _ = Instant::from_ticks(1) - Instant::from_ticks(2);
+    }
+
+    #[test]
+    fn hertz_period() {
+        assert_eq!(Hertz(1_000_000).period(), Duration::from_ticks(100));
+        assert_eq!(Hertz(3).period(), Duration::from_ticks(33_333_333));
+        assert_eq!(Hertz(200_000_000).period(), Duration::from_ticks(1));
+        assert_eq!(Hertz(0).period(), Duration::MAX);
+    }
+
+    #[test]
+    fn clock_reads_mtime_without_owning_clint() {
+        let session = mock::session();
+        let clint = CLINT::new();
+        let clock = Clock::new(&clint);
+        session.write(CLINT_MTIME + 0x04, 500);
+        let start: Instant = clock.now();
+        assert_eq!(start, Instant::from_ticks(500));
+        session.write(CLINT_MTIME + 0x04, 800);
+        assert_eq!(clock.elapsed(start), Duration::from_ticks(300));
+        assert!(clock.has_elapsed(start, Duration::from_ticks(300)));
+        assert!(!clock.has_elapsed(start, Duration::from_ticks(301)));
+        assert_eq!(clint.now(), 800);
+    }
+}